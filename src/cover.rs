@@ -0,0 +1,309 @@
+use std::convert::TryInto;
+#[cfg(feature = "tagging")]
+use std::convert::TryFrom;
+#[cfg(feature = "tagging")]
+use crate::error::{DumpError, DumpResult};
+
+/// largest cover that is decoded, 4096x4096; the size comes from the file so it can't be
+/// trusted with an allocation
+#[cfg(feature = "tagging")]
+const MAX_COVER_PIXELS: usize = 4096 * 4096;
+
+/// quality on the usual 1-100 scale, high enough that covers don't show blocking
+#[cfg(feature = "tagging")]
+const JPEG_QUALITY: u8 = 90;
+
+/// Decode the first frame of a GIF and re-encode it as a PNG.
+#[cfg(feature = "tagging")]
+pub(crate) fn gif_first_frame(data: &[u8]) -> DumpResult<Vec<u8>> {
+    let (canvas, width, height) = gif_pixels(data)?;
+    encode_png(&canvas, width, height)
+}
+
+/// Re-encode a PNG or GIF cover as a JPEG, only the first frame of a GIF is kept.
+#[cfg(feature = "tagging")]
+pub(crate) fn to_jpeg(data: &[u8]) -> DumpResult<Vec<u8>> {
+    let (pixels, width, height) = match data {
+        [b'G', b'I', b'F', ..] => gif_pixels(data)?,
+        _ => png_pixels(data)?,
+    };
+    encode_jpeg(&pixels, width, height)
+}
+
+/// Encode RGBA pixels as a JPEG.
+///
+/// JPEG has no alpha channel, transparent pixels are blended onto white. The frame header
+/// stores the size in 16 bits, larger or empty images are an error.
+#[cfg(feature = "tagging")]
+fn encode_jpeg(rgba: &[u8], width: usize, height: usize) -> DumpResult<Vec<u8>> {
+    let (frame_width, frame_height) = match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(frame_width), Ok(frame_height)) if frame_width > 0 && frame_height > 0 => (frame_width, frame_height),
+        _ => return Err(DumpError::InvalidCover(format!("can't encode {}x{} pixels as jpeg", width, height))),
+    };
+    let rgb: Vec<u8> = rgba.chunks(4)
+        .flat_map(|pixel| {
+            let alpha = pixel[3] as u32;
+            (0..3).map(move |c| ((pixel[c] as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8)
+        })
+        .collect();
+    let mut output = vec![];
+    jpeg_encoder::Encoder::new(&mut output, JPEG_QUALITY)
+        .encode(&rgb, frame_width, frame_height, jpeg_encoder::ColorType::Rgb)
+        .map_err(|err| DumpError::InvalidCover(format!("failed to encode it as jpeg: {}", err)))?;
+    Ok(output)
+}
+
+/// RGBA pixels of the first frame of a GIF.
+///
+/// The frame is composited onto a transparent canvas of the logical screen size, so covers
+/// whose first frame only covers part of the image keep their dimensions.
+#[cfg(feature = "tagging")]
+fn gif_pixels(data: &[u8]) -> DumpResult<(Vec<u8>, usize, usize)> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(data).map_err(malformed)?;
+    let width = decoder.width() as usize;
+    let height = decoder.height() as usize;
+    let canvas_len = width.checked_mul(height)
+        .filter(|&pixels| pixels <= MAX_COVER_PIXELS)
+        .ok_or_else(|| DumpError::InvalidCover(format!("a {}x{} gif is too large to decode", width, height)))?
+        * 4;
+    let frame = decoder.read_next_frame()
+        .map_err(malformed)?
+        .ok_or_else(|| DumpError::InvalidCover("gif has no frames".to_string()))?;
+    if frame.width == 0 || frame.height == 0 {
+        return Err(DumpError::InvalidCover("the first gif frame is empty".to_string()));
+    }
+
+    let mut canvas = vec![0u8; canvas_len];
+    let frame_width = frame.width as usize;
+    for (y, row) in frame.buffer.chunks(frame_width * 4).enumerate() {
+        let top = frame.top as usize + y;
+        let left = frame.left as usize;
+        if top >= height || left >= width {
+            continue;
+        }
+        let len = row.len().min((width - left) * 4);
+        let start = (top * width + left) * 4;
+        canvas[start..start + len].copy_from_slice(&row[..len]);
+    }
+    Ok((canvas, width, height))
+}
+
+/// Re-encode a PNG at half its size until it takes at most `max_bytes`.
+///
+/// Each step averages 2x2 blocks of pixels; fails if the image gets down to a single row or
+/// column before it fits.
+#[cfg(feature = "tagging")]
+pub(crate) fn shrink_png(data: &[u8], max_bytes: usize) -> DumpResult<Vec<u8>> {
+    let (mut pixels, mut width, mut height) = png_pixels(data)?;
+    loop {
+        if width < 2 || height < 2 {
+            return Err(DumpError::InvalidCover(format!("can't shrink it below {} bytes", max_bytes)));
+        }
+        pixels = halve(&pixels, width, height);
+        width /= 2;
+        height /= 2;
+        let output = encode_png(&pixels, width, height)?;
+        if output.len() <= max_bytes {
+            return Ok(output);
+        }
+    }
+}
+
+/// RGBA pixels of a PNG along with its width and height
+#[cfg(feature = "tagging")]
+fn png_pixels(data: &[u8]) -> DumpResult<(Vec<u8>, usize, usize)> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(decode_error)?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(decode_error)?;
+    let pixels = buf[..info.buffer_size()]
+        .chunks(info.color_type.samples())
+        .flat_map(|pixel| match *pixel {
+            [gray] => [gray, gray, gray, 0xFF],
+            [gray, alpha] => [gray, gray, gray, alpha],
+            [r, g, b] => [r, g, b, 0xFF],
+            [r, g, b, a] => [r, g, b, a],
+            _ => [0; 4],
+        })
+        .collect();
+    Ok((pixels, info.width as usize, info.height as usize))
+}
+
+/// RGBA pixels at half the width and height, every pixel the average of a 2x2 block
+#[cfg(feature = "tagging")]
+fn halve(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity((width / 2) * (height / 2) * 4);
+    for y in 0..height / 2 {
+        for x in 0..width / 2 {
+            for channel in 0..4 {
+                let at = |dx: usize, dy: usize| pixels[((y * 2 + dy) * width + x * 2 + dx) * 4 + channel] as u16;
+                output.push(((at(0, 0) + at(1, 0) + at(0, 1) + at(1, 1)) / 4) as u8);
+            }
+        }
+    }
+    output
+}
+
+#[cfg(feature = "tagging")]
+fn encode_png(rgba: &[u8], width: usize, height: usize) -> DumpResult<Vec<u8>> {
+    let mut output = vec![];
+    let mut encoder = png::Encoder::new(&mut output, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(encode_error)?;
+    writer.write_image_data(rgba).map_err(encode_error)?;
+    writer.finish().map_err(encode_error)?;
+    Ok(output)
+}
+
+/// Width and height read from the image header without decoding it, for PNG, JPEG, GIF
+/// and WebP.
+pub(crate) fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let be32 = |offset: usize| data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    let le16 = |offset: usize| data.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32);
+    let le24 = |offset: usize| data.get(offset..offset + 3).map(|b| u32::from_le_bytes([b[0], b[1], b[2], 0]));
+    let dimensions = match data {
+        [137, 80, 78, 71, 13, 10, 26, 10, _, _, _, _, b'I', b'H', b'D', b'R', ..] => (be32(16)?, be32(20)?),
+        [b'G', b'I', b'F', ..] => (le16(6)?, le16(8)?),
+        [0xFF, 0xD8, 0xFF, ..] => jpeg_dimensions(data)?,
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', b'V', b'P', b'8', chunk, ..] => match chunk {
+            b' ' => (le16(26)? & 0x3FFF, le16(28)? & 0x3FFF),
+            b'L' => {
+                let bits = u32::from_le_bytes(data.get(21..25)?.try_into().ok()?);
+                ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1)
+            },
+            b'X' => (le24(24)? + 1, le24(27)? + 1),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(dimensions)
+}
+
+/// walk the JPEG segments up to the first start-of-frame marker
+fn jpeg_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let mut offset = 2;
+    loop {
+        let marker = *data.get(offset + 1)?;
+        if data[offset] != 0xFF {
+            return None;
+        }
+        // SOF0..SOF15, except DHT, JPG and DAC which share the range
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            let height = u16::from_be_bytes(data.get(offset + 5..offset + 7)?.try_into().ok()?);
+            let width = u16::from_be_bytes(data.get(offset + 7..offset + 9)?.try_into().ok()?);
+            return Some((width as u32, height as u32));
+        }
+        let len = u16::from_be_bytes(data.get(offset + 2..offset + 4)?.try_into().ok()?);
+        offset += 2 + len as usize;
+    }
+}
+
+#[cfg(feature = "tagging")]
+fn malformed(err: gif::DecodingError) -> DumpError {
+    DumpError::InvalidCover(format!("malformed gif: {}", err))
+}
+
+#[cfg(feature = "tagging")]
+fn decode_error(err: png::DecodingError) -> DumpError {
+    DumpError::InvalidCover(format!("malformed png: {}", err))
+}
+
+#[cfg(feature = "tagging")]
+fn encode_error(err: png::EncodingError) -> DumpError {
+    DumpError::InvalidCover(format!("failed to encode it as png: {}", err))
+}
+
+#[cfg(all(test, feature = "tagging"))]
+mod tests {
+    use super::*;
+
+    /// a GIF with a two colour palette whose first frame is `frame_width`x`frame_height`
+    fn gif(width: u16, height: u16, frame_width: u16, frame_height: u16, lzw: &[u8]) -> Vec<u8> {
+        let mut data = b"GIF89a".to_vec();
+        data.extend_from_slice(&width.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&[0x80, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF]);
+        data.extend_from_slice(&[0x2C, 0, 0, 0, 0]);
+        data.extend_from_slice(&frame_width.to_le_bytes());
+        data.extend_from_slice(&frame_height.to_le_bytes());
+        data.extend_from_slice(&[0, 2, lzw.len() as u8]);
+        data.extend_from_slice(lzw);
+        data.extend_from_slice(&[0, 0x3B]);
+        data
+    }
+
+    /// clear code, colour 0, end code
+    const ONE_PIXEL: [u8; 2] = [0x44, 0x01];
+
+    #[test]
+    fn decodes_a_single_pixel() {
+        let (canvas, width, height) = gif_pixels(&gif(1, 1, 1, 1, &ONE_PIXEL)).unwrap();
+        assert_eq!((width, height), (1, 1));
+        assert_eq!(canvas, [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn rejects_huge_logical_screens() {
+        let err = gif_pixels(&gif(65535, 65535, 1, 1, &ONE_PIXEL)).unwrap_err();
+        assert!(matches!(err, DumpError::InvalidCover(_)), "{}", err);
+    }
+
+    /// a smooth gradient that doesn't fill whole 8x8 blocks
+    fn gradient(width: usize, height: usize) -> Vec<u8> {
+        (0..width * height)
+            .flat_map(|i| [(i % width * 255 / width) as u8, (i / width * 255 / height) as u8, 0x80, 0xFF])
+            .collect()
+    }
+
+    /// RGB pixels, width and height of a JPEG
+    fn decode_jpeg(data: &[u8]) -> (Vec<u8>, usize, usize) {
+        let mut decoder = jpeg_decoder::Decoder::new(data);
+        let pixels = decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        assert_eq!(info.pixel_format, jpeg_decoder::PixelFormat::RGB24);
+        (pixels, info.width as usize, info.height as usize)
+    }
+
+    fn assert_close(rgba: &[u8], rgb: &[u8]) {
+        let errors: Vec<u32> = rgba.chunks(4).zip(rgb.chunks(3))
+            .flat_map(|(expected, actual)| (0..3).map(move |c| (expected[c] as i32 - actual[c] as i32).unsigned_abs()))
+            .collect();
+        let mean = errors.iter().sum::<u32>() as f32 / errors.len() as f32;
+        assert!(mean < 3.0, "mean error {}", mean);
+        assert!(errors.iter().all(|&error| error < 32), "max error {:?}", errors.iter().max());
+    }
+
+    #[test]
+    fn converts_png_covers_to_jpeg() {
+        let rgba = gradient(40, 24);
+        let png = encode_png(&rgba, 40, 24).unwrap();
+        let (rgb, width, height) = decode_jpeg(&to_jpeg(&png).unwrap());
+        assert_eq!((width, height), (40, 24));
+        assert_close(&rgba, &rgb);
+    }
+
+    #[test]
+    fn blends_transparency_onto_white() {
+        let (rgb, _, _) = decode_jpeg(&encode_jpeg(&[0, 0, 0, 0].repeat(64), 8, 8).unwrap());
+        assert!(rgb.iter().all(|&value| value >= 254), "{:?}", rgb);
+    }
+
+    #[test]
+    fn rejects_sizes_the_jpeg_frame_header_cannot_hold() {
+        let err = encode_jpeg(&[], 0, 8).unwrap_err();
+        assert!(matches!(err, DumpError::InvalidCover(_)));
+        assert_eq!(err.to_string(), "Invalid cover: can't encode 0x8 pixels as jpeg");
+        assert!(encode_jpeg(&[0; 4], 65536, 1).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_frames() {
+        // clear code, end code
+        assert!(gif_pixels(&gif(1, 1, 0, 1, &[0x2C])).is_err());
+        assert!(gif_pixels(&gif(1, 1, 1, 0, &[0x2C])).is_err());
+    }
+}
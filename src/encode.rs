@@ -0,0 +1,68 @@
+use block_modes::{Ecb, BlockMode};
+use aes::Aes128;
+use block_modes::block_padding::Pkcs7;
+use sha2::{Digest, Sha256};
+use crate::error::DumpResult;
+use crate::ncm::{build_key_box, decode_audio, hex_digest, Metadata, CORE_KEY, KEY_PREFIX, MAGIC_HEADER, METADATA_PREFIX, MODIFY_KEY};
+
+/// the version bytes written after the magic header, the same as NetEase's own files
+const VERSION: [u8; 2] = [0x01, 0x70];
+
+/// Build an ncm file from plain audio, the reverse of `decode_ncm`.
+///
+/// The RC4 key is derived from the audio, so encoding the same input twice gives the same file.
+pub fn encode_ncm(audio: &[u8], metadata: Option<&Metadata>, cover: Option<&[u8]>) -> DumpResult<Vec<u8>> {
+    encode_ncm_with_keys(audio, metadata, cover, CORE_KEY, MODIFY_KEY)
+}
+
+/// Like `encode_ncm`, encrypting the key and metadata blocks with other AES keys.
+pub fn encode_ncm_with_keys(
+    audio: &[u8],
+    metadata: Option<&Metadata>,
+    cover: Option<&[u8]>,
+    core_key: [u8; 16],
+    modify_key: [u8; 16],
+) -> DumpResult<Vec<u8>> {
+    let rc4_key = hex_digest(Sha256::new_with_prefix(audio));
+    let key_box = build_key_box(rc4_key.as_bytes())?;
+
+    let mut output = MAGIC_HEADER.to_vec();
+    output.extend_from_slice(&VERSION);
+
+    let mut key_data = aes_encrypt(&[KEY_PREFIX, rc4_key.as_bytes()].concat(), &core_key)?;
+    key_data.iter_mut().for_each(|b| *b ^= 0x64);
+    write_block(&mut output, &key_data);
+
+    match metadata {
+        Some(metadata) => {
+            let json = serde_json::to_string(metadata)?;
+            let encrypted = aes_encrypt(&[b"music:", json.as_bytes()].concat(), &modify_key)?;
+            let mut meta_data = [METADATA_PREFIX, base64::encode(encrypted).as_bytes()].concat();
+            meta_data.iter_mut().for_each(|b| *b ^= 0x63);
+            write_block(&mut output, &meta_data);
+        },
+        None => write_block(&mut output, &[]),
+    }
+
+    // crc32 and gap, the decoder skips both
+    output.extend_from_slice(&[0; 9]);
+    write_block(&mut output, cover.unwrap_or_default());
+
+    let audio_start = output.len();
+    output.extend_from_slice(audio);
+    // the keystream is xored, so decoding plain audio encrypts it
+    decode_audio(&mut output[audio_start..], 0, &key_box);
+    Ok(output)
+}
+
+fn aes_encrypt(data: &[u8], key: &[u8; 16]) -> DumpResult<Vec<u8>> {
+    let cipher = Ecb::<Aes128, Pkcs7>::new_var(key, Default::default())?;
+    Ok(cipher.encrypt_vec(data))
+}
+
+/// a block prefixed with its length as a little-endian u32
+fn write_block(output: &mut Vec<u8>, block: &[u8]) {
+    output.extend_from_slice(&(block.len() as u32).to_le_bytes());
+    output.extend_from_slice(block);
+}
+
@@ -0,0 +1,179 @@
+use std::fmt;
+
+/// result of every fallible step of a dump
+pub(crate) type DumpResult<T> = Result<T, DumpError>;
+
+/// Everything that can go wrong while decoding.
+///
+/// It is `Send + Sync + 'static`, so native callers can `?` it into their own error types or
+/// into `anyhow::Error`:
+///
+/// ```
+/// fn foo() -> anyhow::Result<()> {
+///     let ncm = ncmdump_wasm::encode_ncm(&[0xFF, 0xFB, 0x90, 0x64], None, None)?;
+///     let decoded = ncmdump_wasm::decode_ncm(&ncm)?;
+///     assert_eq!(decoded.extension, "mp3");
+///     Ok(())
+/// }
+///
+/// fn truncated() -> anyhow::Result<()> {
+///     ncmdump_wasm::decode_ncm(b"CTENFDAM")?;
+///     Ok(())
+/// }
+///
+/// foo().unwrap();
+/// let err = truncated().unwrap_err();
+/// assert!(matches!(err.downcast_ref(), Some(ncmdump_wasm::DumpError::TruncatedHeader)));
+/// ```
+#[derive(Debug)]
+pub enum DumpError {
+    /// magic header mismatch, holds the first bytes that were found instead
+    NotNcmFormat(Vec<u8>),
+    /// the file was recognized as another format, usually another music service's
+    WrongFormat(String),
+    /// the file ended before a complete block could be read
+    TruncatedHeader,
+    /// the two bytes after the magic header aren't a known version, only an error in strict
+    /// mode
+    UnsupportedVersion(u16),
+    AesError(String),
+    /// the key block couldn't be decrypted with the core key
+    KeyDecryptFailed(String),
+    /// the metadata block couldn't be decrypted with the modify key
+    MetadataDecryptFailed(String),
+    /// the metadata block is too small to hold the prefixes and an encrypted payload
+    MetadataTooShort,
+    /// the file has no metadata block, only an error in strict mode
+    MissingMetadata,
+    MetadataParse(String),
+    /// the format named in the metadata isn't the one detected from the audio, only an error
+    /// in strict mode
+    FormatMismatch { declared: String, detected: String },
+    /// the audio was detected as FLAC but the `fLaC` marker isn't where it should be
+    InvalidFlacStream,
+    /// the file ends right after the cover, there is no audio to decode
+    NoAudioData,
+    TagError(String),
+    /// the cover couldn't be decoded, converted or shrunk
+    InvalidCover(String),
+    /// the caller asked to stop before the audio was decoded
+    Cancelled,
+    Io(std::io::Error),
+}
+
+impl DumpError {
+    /// short, stable identifier that can be matched on from JS
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NotNcmFormat(_) => "not_ncm",
+            Self::WrongFormat(_) => "wrong_format",
+            Self::TruncatedHeader => "truncated",
+            Self::UnsupportedVersion(_) => "unsupported_version",
+            Self::AesError(_) => "aes",
+            Self::KeyDecryptFailed(_) => "key_decrypt",
+            Self::MetadataDecryptFailed(_) => "metadata_decrypt",
+            Self::MetadataTooShort => "metadata_too_short",
+            Self::MissingMetadata => "missing_metadata",
+            Self::MetadataParse(_) => "metadata_parse",
+            Self::FormatMismatch { .. } => "format_mismatch",
+            Self::InvalidFlacStream => "invalid_flac",
+            Self::NoAudioData => "no_audio",
+            Self::TagError(_) => "tag",
+            Self::InvalidCover(_) => "invalid_cover",
+            Self::Cancelled => "cancelled",
+            Self::Io(_) => "io",
+        }
+    }
+}
+
+impl fmt::Display for DumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotNcmFormat(header) if header.is_empty() => write!(f, "This file is not in ncm format, it is empty"),
+            Self::NotNcmFormat(header) => {
+                let header = header.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+                write!(f, "This file is not in ncm format, it starts with {} instead of `CTENFDAM`", header)
+            },
+            Self::WrongFormat(format) => write!(f, "This is a {} file, not ncm", format),
+            Self::TruncatedHeader => write!(f, "Unexpected end of file"),
+            Self::UnsupportedVersion(version) => write!(f, "Unsupported ncm format version {:#06x}", version),
+            Self::AesError(err) => write!(f, "AES decryption failed: {}", err),
+            Self::KeyDecryptFailed(err) => write!(f, "Failed to decrypt the key block: {}", err),
+            Self::MetadataDecryptFailed(err) => write!(f, "Failed to decrypt the metadata block: {}", err),
+            Self::MetadataTooShort => write!(f, "Metadata block is too short"),
+            Self::MissingMetadata => write!(f, "The file has no metadata"),
+            Self::MetadataParse(err) => write!(f, "Failed to parse metadata: {}", err),
+            Self::FormatMismatch { declared, detected } => {
+                write!(f, "Metadata says the audio is {} but it was detected as {}", declared, detected)
+            },
+            Self::InvalidFlacStream => write!(f, "FLAC stream doesn't start with `fLaC`"),
+            Self::NoAudioData => write!(f, "The file contains no audio data"),
+            Self::TagError(err) => write!(f, "Failed to write tags: {}", err),
+            Self::InvalidCover(err) => write!(f, "Invalid cover: {}", err),
+            Self::Cancelled => write!(f, "Decoding was cancelled"),
+            Self::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+// keep the error usable across threads and in boxed or `anyhow` errors
+const _: fn() = || {
+    fn assert_error<T: std::error::Error + Send + Sync + 'static>() {}
+    assert_error::<DumpError>();
+};
+
+impl std::error::Error for DumpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DumpError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => Self::TruncatedHeader,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+impl From<block_modes::InvalidKeyIvLength> for DumpError {
+    fn from(err: block_modes::InvalidKeyIvLength) -> Self {
+        Self::AesError(err.to_string())
+    }
+}
+
+impl From<block_modes::BlockModeError> for DumpError {
+    fn from(err: block_modes::BlockModeError) -> Self {
+        Self::AesError(err.to_string())
+    }
+}
+
+impl From<base64::DecodeError> for DumpError {
+    fn from(err: base64::DecodeError) -> Self {
+        Self::MetadataParse(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for DumpError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::MetadataParse(err.to_string())
+    }
+}
+
+#[cfg(feature = "tagging")]
+impl From<metaflac::Error> for DumpError {
+    fn from(err: metaflac::Error) -> Self {
+        Self::TagError(err.to_string())
+    }
+}
+
+#[cfg(feature = "tagging")]
+impl From<id3::Error> for DumpError {
+    fn from(err: id3::Error) -> Self {
+        Self::TagError(err.to_string())
+    }
+}
@@ -0,0 +1,69 @@
+use wasm_bindgen::prelude::*;
+use crate::ncm::Metadata;
+
+/// characters that are not allowed in file names on Windows or macOS
+const ILLEGAL_CHARS: [char; 9] = ['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+#[wasm_bindgen]
+pub struct FilenameOptions {
+    template: String,
+    artist_separator: String,
+    max_length: usize,
+}
+
+#[wasm_bindgen]
+impl FilenameOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            template: "{artist} - {title}".to_string(),
+            artist_separator: ", ".to_string(),
+            max_length: 200,
+        }
+    }
+
+    /// supported placeholders: `{artist}`, `{title}`, `{album}`
+    pub fn set_template(&mut self, template: String) {
+        self.template = template;
+    }
+
+    pub fn set_artist_separator(&mut self, separator: String) {
+        self.artist_separator = separator;
+    }
+
+    /// maximum length of the name in characters, not counting the extension
+    pub fn set_max_length(&mut self, max_length: usize) {
+        self.max_length = max_length;
+    }
+}
+
+impl Default for FilenameOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) fn build_filename(metadata: Option<&Metadata>, extension: &str, options: &FilenameOptions) -> String {
+    let name = metadata.map(|metadata| {
+        let artist = metadata.artists().iter()
+            .map(|artist| artist.name())
+            .collect::<Vec<_>>()
+            .join(&options.artist_separator);
+        options.template
+            .replace("{artist}", &artist)
+            .replace("{title}", &metadata.music_name())
+            .replace("{album}", &metadata.album())
+    });
+    let name = name.map(|name| sanitize(&name, options.max_length))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "output".to_string());
+    format!("{}.{}", name, extension)
+}
+
+fn sanitize(name: &str, max_length: usize) -> String {
+    let name = name.chars()
+        .map(|c| if ILLEGAL_CHARS.contains(&c) || c.is_control() { '_' } else { c })
+        .take(max_length)
+        .collect::<String>();
+    name.trim().trim_end_matches('.').to_string()
+}
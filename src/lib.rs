@@ -1,9 +1,28 @@
+mod cover;
+mod encode;
+mod error;
+mod filename;
 mod ncm;
+#[cfg(feature = "tagging")]
+mod ogg;
+mod options;
+mod stream;
+#[cfg(feature = "tagging")]
+mod tagging;
 
 use wasm_bindgen::prelude::*;
 use std::sync::Once;
-use crate::ncm::{NcmDump, DumpOutput};
+use crate::ncm::{cancel_callback, progress_callback, NcmDecoder, NcmDump, DumpOutput, MetadataOutput};
 
+pub use crate::encode::{encode_ncm, encode_ncm_with_keys};
+pub use crate::error::DumpError;
+pub use crate::ncm::{decode_ncm, decode_ncm_with_keys, decode_ncm_with_options, dump_into, is_ncm, DecodedNcm, Metadata, Artist, Cover, OutputFormat, Timings};
+pub use crate::options::{CoverFormat, CoverPolicy, DumpOptions, GifCoverPolicy};
+pub use crate::stream::DumpStream;
+
+const DEFAULT_LOG_LEVEL: log::LevelFilter = log::LevelFilter::Debug;
+
+#[cfg(feature = "wee_alloc")]
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
@@ -11,11 +30,123 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 pub fn initialize() {
     console_error_panic_hook::set_once();
     Once::new().call_once(|| {
-        wasm_logger::init(Default::default());
+        // the logger lets everything through, `set_log_level` decides what is actually logged
+        wasm_logger::init(wasm_logger::Config::new(log::Level::Trace));
+        log::set_max_level(DEFAULT_LOG_LEVEL);
     });
 }
 
+/// Only log messages at `level` or above: one of `off`, `error`, `warn`, `info`, `debug` or
+/// `trace`, case-insensitive. `off` silences the warnings about files without metadata or cover,
+/// they are still returned by `DumpOutput::warnings`. Defaults to `debug`.
+#[wasm_bindgen]
+pub fn set_log_level(level: &str) -> Result<(), JsValue> {
+    let level = level.parse::<log::LevelFilter>()
+        .map_err(|_| JsValue::from_str(&format!("unknown log level `{}`", level)))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+#[wasm_bindgen]
+pub fn dump(data: Vec<u8>, on_progress: Option<js_sys::Function>) -> DumpOutput {
+    dump_owned(data, &DumpOptions::default(), on_progress)
+}
+
+/// Same as `dump`, for a Node `Buffer` or any `Uint8Array`. The bytes are copied into wasm
+/// memory like for `dump`, and that copy is freed before tagging.
+#[wasm_bindgen]
+pub fn dump_buffer(data: Vec<u8>, on_progress: Option<js_sys::Function>) -> DumpOutput {
+    dump_owned(data, &DumpOptions::default(), on_progress)
+}
+
+/// Same as `dump`, decoding straight from the bytes wasm-bindgen copied in. An `NcmDump` has
+/// to own its input, so this avoids the second copy `new_from_memory` would take.
 #[wasm_bindgen]
-pub fn dump(data: Vec<u8>) -> DumpOutput {
-    NcmDump::new_from_memory(data).dump()
+pub fn dump_slice(data: &[u8], on_progress: Option<js_sys::Function>) -> DumpOutput {
+    match NcmDecoder::new(data).dump(&DumpOptions::default(), &mut progress_callback(&on_progress)) {
+        Ok(decoded) => DumpOutput::from(decoded),
+        Err(err) => DumpOutput::from_error(err),
+    }
+}
+
+/// Same as `dump`, returning to the event loop while decrypting so the page doesn't freeze on
+/// big files. Decoding still runs on the main thread, a worker keeps it off entirely.
+#[wasm_bindgen]
+pub async fn dump_async(data: Vec<u8>, on_progress: Option<js_sys::Function>) -> DumpOutput {
+    match NcmDecoder::new(data).releasing_input().dump_async(&DumpOptions::default(), &mut progress_callback(&on_progress)).await {
+        Ok(decoded) => DumpOutput::from(decoded),
+        Err(err) => DumpOutput::from_error(err),
+    }
+}
+
+/// Same as `dump`, `should_cancel` is called after every decrypted chunk and the dump stops with
+/// the `cancelled` error code as soon as it returns a truthy value.
+#[wasm_bindgen]
+pub fn dump_cancellable(data: Vec<u8>, should_cancel: &js_sys::Function, on_progress: Option<js_sys::Function>) -> DumpOutput {
+    let options = DumpOptions::default();
+    match NcmDecoder::new(data).releasing_input().dump_cancellable(&options, &mut progress_callback(&on_progress), &mut cancel_callback(should_cancel)) {
+        Ok(decoded) => DumpOutput::from(decoded),
+        Err(err) => DumpOutput::from_error(err),
+    }
+}
+
+#[wasm_bindgen]
+pub fn dump_with_options(data: Vec<u8>, options: &DumpOptions, on_progress: Option<js_sys::Function>) -> DumpOutput {
+    dump_owned(data, options, on_progress)
+}
+
+#[wasm_bindgen]
+pub fn dump_with_lyrics(data: Vec<u8>, lyrics: Option<String>) -> DumpOutput {
+    let mut options = DumpOptions::default();
+    options.set_lyrics(lyrics);
+    dump_owned(data, &options, None)
+}
+
+/// Decode only the first `max_bytes` of audio for a quick preview, without tagging.
+#[wasm_bindgen]
+pub fn dump_preview(data: Vec<u8>, max_bytes: usize) -> DumpOutput {
+    NcmDump::new_from_memory(data).dump_preview(max_bytes)
+}
+
+/// Decode without tagging; metadata and cover are returned next to the untouched audio.
+#[wasm_bindgen]
+pub fn dump_separated(data: Vec<u8>, on_progress: Option<js_sys::Function>) -> DumpOutput {
+    NcmDump::new_from_memory(data).dump_separated(on_progress)
+}
+
+#[wasm_bindgen]
+pub fn dump_metadata(data: Vec<u8>) -> MetadataOutput {
+    NcmDump::new_from_memory(data).metadata_only()
+}
+
+#[wasm_bindgen]
+pub async fn dump_stream(stream: web_sys::ReadableStream, on_chunk: js_sys::Function) -> DumpOutput {
+    stream::dump_stream(stream, on_chunk).await
+}
+
+/// Decode into a `WritableStream`, e.g. one from the File System Access API, without holding
+/// the whole output in wasm memory. Untagged, the audio is written chunk by chunk as it is
+/// decrypted; with `tag` the audio has to be tagged in memory first, so only writing it out is
+/// chunked. The returned output carries metadata, cover and extension but no data, and the
+/// stream is aborted if the dump fails.
+#[wasm_bindgen]
+pub async fn dump_to_writable(data: Vec<u8>, sink: web_sys::WritableStream, tag: bool) -> DumpOutput {
+    stream::dump_to_writable(data, sink, tag).await
+}
+
+/// Decode several files in one call. Each file is copied into wasm memory only while it is
+/// being decoded, failures are reported in the matching `DumpOutput`.
+#[wasm_bindgen]
+pub fn dump_batch(files: Vec<js_sys::Uint8Array>) -> Vec<DumpOutput> {
+    files.iter()
+        .map(|file| dump_owned(file.to_vec(), &DumpOptions::default(), None))
+        .collect()
+}
+
+/// dump a buffer nobody else needs, freeing it before the audio is tagged
+fn dump_owned(data: Vec<u8>, options: &DumpOptions, on_progress: Option<js_sys::Function>) -> DumpOutput {
+    match NcmDecoder::new(data).releasing_input().dump(options, &mut progress_callback(&on_progress)) {
+        Ok(decoded) => DumpOutput::from(decoded),
+        Err(err) => DumpOutput::from_error(err),
+    }
 }
@@ -1,23 +1,94 @@
 use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
 use serde::{Serialize, Deserialize};
 use wasm_bindgen::__rt::std::io::{Cursor, Seek, Read, Write};
 use std::io::SeekFrom;
 use block_modes::{Ecb, BlockMode};
 use aes::Aes128;
 use block_modes::block_padding::Pkcs7;
+use std::convert::{TryFrom, TryInto};
 use log::warn;
+use sha2::{Digest, Sha256};
+use crate::cover;
+use crate::error::{DumpError, DumpResult};
+use crate::filename::{build_filename, FilenameOptions};
+use crate::options::{DumpOptions, DEFAULT_AUDIO_CHUNK_SIZE};
+use crate::stream::DumpStream;
+#[cfg(feature = "tagging")]
+use crate::tagging;
 
-const CORE_KEY: [u8; 16] = [0x68, 0x7A, 0x48, 0x52, 0x41, 0x6D, 0x73, 0x6F, 0x35, 0x6B, 0x49, 0x6E, 0x62, 0x61, 0x78, 0x57];
+pub(crate) const CORE_KEY: [u8; 16] = [0x68, 0x7A, 0x48, 0x52, 0x41, 0x6D, 0x73, 0x6F, 0x35, 0x6B, 0x49, 0x6E, 0x62, 0x61, 0x78, 0x57];
 
-const MODIFY_KEY: [u8; 16] = [0x23, 0x31, 0x34, 0x6C, 0x6A, 0x6B, 0x5F, 0x21, 0x5C, 0x5D, 0x26, 0x30, 0x55, 0x3C, 0x27, 0x28];
+pub(crate) const MODIFY_KEY: [u8; 16] = [0x23, 0x31, 0x34, 0x6C, 0x6A, 0x6B, 0x5F, 0x21, 0x5C, 0x5D, 0x26, 0x30, 0x55, 0x3C, 0x27, 0x28];
 
-const MAGIC_HEADER: [u8; 8] = *b"CTENFDAM";
+pub(crate) const MAGIC_HEADER: [u8; 8] = *b"CTENFDAM";
 
-type DumpResult<T> = Result<T, String>;
+/// how far into the file the magic header may start, some tools prepend a few bytes
+pub(crate) const MAGIC_SEARCH_WINDOW: usize = 64;
+
+pub(crate) const KEY_PREFIX: &[u8] = b"neteasecloudmusic";
+
+pub(crate) const METADATA_PREFIX: &[u8] = b"163 key(Don't modify):";
+
+/// picture type of the front cover, shared by FLAC and ID3
+const COVER_FRONT_PICTURE_TYPE: u8 = 3;
+
+/// how long `dump_async` decrypts before giving the event loop a turn, about one frame
+const YIELD_INTERVAL_MS: f64 = 16.0;
+
+/// magic headers of files that are often mistaken for ncm, with the name shown to the user
+const FOREIGN_HEADERS: [(&[u8], &str); 3] = [
+    (&[0x7C, 0xD5, 0x32, 0xEB, 0x86, 0x02, 0x7F, 0x4B, 0xA8, 0xAF, 0xA6, 0x8E, 0x0F, 0xFF, 0x99, 0x14], "Kugou (kgm)"),
+    (&[0x05, 0x28, 0xBC, 0x96, 0xE9, 0xE4, 0x5A, 0x43, 0x91, 0xAA, 0xBD, 0xD0, 0x7A, 0xF5, 0x36, 0x31], "Kugou (vpr)"),
+    (b"yeelion-kuwo-tme", "Kuwo (kwm)"),
+];
+
+/// bytes of decoded audio needed to identify its format
+pub(crate) const AUDIO_HEADER_LEN: usize = 12;
+
+/// the two bytes after the magic header as written by the NetEase client
+const NCM_VERSION: FormatVersion = FormatVersion { version: 0x01, reserved: 0x70 };
+
+/// values of the two bytes after the magic header that are known to use the usual layout;
+/// files converted by other tools often leave them zeroed. Others are only rejected in strict
+/// mode, the layout after them has never been seen to change
+const KNOWN_VERSIONS: [FormatVersion; 2] = [NCM_VERSION, FormatVersion { version: 0, reserved: 0 }];
+
+/// the two bytes after the magic header, a format version followed by a reserved byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FormatVersion {
+    pub(crate) version: u8,
+    pub(crate) reserved: u8,
+}
+
+impl FormatVersion {
+    /// both bytes as one little-endian number, `0x7001` for NetEase's files
+    pub(crate) fn as_u16(self) -> u16 {
+        u16::from_le_bytes([self.version, self.reserved])
+    }
+}
+
+/// key box, metadata and cover, everything that precedes the audio
+#[derive(Clone)]
+pub(crate) struct Header {
+    pub(crate) version: FormatVersion,
+    pub(crate) key_box: [u8; 256],
+    pub(crate) metadata: Option<Metadata>,
+    pub(crate) image: Option<Image>,
+}
 
 #[wasm_bindgen]
 pub struct NcmDump {
-    inner: NcmDecoder,
+    inner: NcmDecoder<Vec<u8>>,
+    /// kept by `parse_header` so `decode_audio_now` doesn't have to read the header again
+    parsed: Option<ParsedHeader>,
+}
+
+struct ParsedHeader {
+    header: Header,
+    audio_offset: u64,
+    warnings: Vec<String>,
 }
 
 #[wasm_bindgen]
@@ -25,187 +96,1332 @@ impl NcmDump {
     pub fn new_from_memory(data: Vec<u8>) -> Self {
         Self {
             inner: NcmDecoder::new(data),
+            parsed: None,
+        }
+    }
+
+    /// decode a file whose key and metadata blocks are encrypted with other keys than
+    /// NetEase's, both keys have to be 16 bytes long
+    pub fn new_with_keys(data: Vec<u8>, core_key: &[u8], modify_key: &[u8]) -> Result<NcmDump, JsValue> {
+        let core_key = core_key.try_into().map_err(|_| JsValue::from_str("core key must be 16 bytes"))?;
+        let modify_key = modify_key.try_into().map_err(|_| JsValue::from_str("modify key must be 16 bytes"))?;
+        Ok(Self {
+            inner: NcmDecoder::with_keys(data, core_key, modify_key),
+            parsed: None,
+        })
+    }
+
+    /// decode an ncm file embedded in `data` that starts `offset` bytes in, the bytes before
+    /// it are ignored; the magic header has to be right at `offset`, it isn't searched for
+    pub fn new_from_memory_at(data: Vec<u8>, offset: usize) -> Self {
+        Self {
+            inner: NcmDecoder::new(data).starting_at(offset as u64),
+            parsed: None,
+        }
+    }
+
+    pub fn dump(&mut self, on_progress: Option<js_sys::Function>) -> DumpOutput {
+        self.dump_with_options(&DumpOptions::default(), on_progress)
+    }
+
+    pub fn dump_with_options(&mut self, options: &DumpOptions, on_progress: Option<js_sys::Function>) -> DumpOutput {
+        match self.inner.dump(options, &mut progress_callback(&on_progress)) {
+            Ok(decoded) => DumpOutput::from(decoded),
+            Err(err) => DumpOutput::from_error(err),
+        }
+    }
+
+    /// the decrypted audio exactly as stored, without reading or writing any tags; for
+    /// comparing against other implementations. The output carries no metadata.
+    pub fn dump_raw(&mut self, on_progress: Option<js_sys::Function>) -> DumpOutput {
+        match self.inner.dump_untagged(false, &mut progress_callback(&on_progress)) {
+            Ok(decoded) => DumpOutput::from(decoded),
+            Err(err) => DumpOutput::from_error(err),
+        }
+    }
+
+    /// the first `max_bytes` of audio for previews, untagged; see `dump_preview`
+    pub fn dump_preview(&mut self, max_bytes: usize) -> DumpOutput {
+        match self.inner.dump_preview(max_bytes as u64) {
+            Ok(decoded) => DumpOutput::from(decoded),
+            Err(err) => DumpOutput::from_error(err),
+        }
+    }
+
+    /// untagged audio with the metadata and cover next to it, for callers that write their
+    /// own tags
+    pub fn dump_separated(&mut self, on_progress: Option<js_sys::Function>) -> DumpOutput {
+        match self.inner.dump_untagged(true, &mut progress_callback(&on_progress)) {
+            Ok(decoded) => DumpOutput::from(decoded),
+            Err(err) => DumpOutput::from_error(err),
+        }
+    }
+
+    /// first half of a two-step dump: read metadata and cover, keeping what is needed to
+    /// decode the audio later with `decode_audio_now`
+    pub fn parse_header(&mut self) -> MetadataOutput {
+        match self.inner.read_header(true) {
+            Ok(header) => {
+                let output = MetadataOutput::new(
+                    header.metadata.clone(),
+                    header.image.as_ref().map(|image| image.data.clone()).unwrap_or_default(),
+                    "ok".to_string(),
+                );
+                self.parsed = Some(ParsedHeader {
+                    header,
+                    audio_offset: self.inner.position(),
+                    warnings: self.inner.take_warnings(),
+                });
+                output
+            },
+            Err(err) => MetadataOutput::from_error(err),
+        }
+    }
+
+    /// second half of a two-step dump, decodes the audio with the header kept by
+    /// `parse_header`; reads the header first if it wasn't parsed yet
+    pub fn decode_audio_now(&mut self, options: &DumpOptions, on_progress: Option<js_sys::Function>) -> DumpOutput {
+        let parsed = match &self.parsed {
+            Some(parsed) => parsed,
+            None => return self.dump_with_options(options, on_progress),
+        };
+        self.inner.data.set_position(parsed.audio_offset);
+        self.inner.warnings = parsed.warnings.clone();
+        match self.inner.dump_parsed(parsed.header.clone(), options, &mut progress_callback(&on_progress)) {
+            Ok(decoded) => DumpOutput::from(decoded),
+            Err(err) => DumpOutput::from_error(err),
         }
     }
 
-    pub fn dump(&mut self) -> DumpOutput {
-        match self.inner.dump() {
-            Ok((data, metadata, extension)) => DumpOutput::new(data, metadata,"ok".to_string(), extension),
-            Err(err) => DumpOutput::new(vec![], "".to_string(), err, "".to_string()),
+    pub fn metadata_only(&mut self) -> MetadataOutput {
+        match self.inner.read_header(true) {
+            Ok(header) => {
+                MetadataOutput::new(header.metadata, header.image.map(|image| image.data).unwrap_or_default(), "ok".to_string())
+            },
+            Err(err) => MetadataOutput::from_error(err),
+        }
+    }
+
+    /// XOR keystream for `len` bytes starting at `offset` within the audio stream,
+    /// empty when the header can't be read
+    pub fn keystream_at(&mut self, offset: usize, len: usize) -> Vec<u8> {
+        match self.inner.keystream_at(offset, len) {
+            Ok(keystream) => keystream,
+            Err(err) => {
+                warn!("Failed to build keystream: {}", err);
+                vec![]
+            },
+        }
+    }
+
+    /// extension `dump` would produce, found by decrypting only the start of the audio;
+    /// empty when the header can't be read
+    pub fn peek_extension(&mut self) -> String {
+        match self.inner.peek_format() {
+            Ok(format) => format.extension().to_string(),
+            Err(err) => {
+                warn!("Failed to detect audio format: {}", err);
+                "".to_string()
+            },
+        }
+    }
+
+    /// the decrypted metadata JSON without parsing it, for files whose metadata `dump` can't
+    /// read; `undefined` when the file has no metadata or it can't be decrypted
+    pub fn raw_metadata_json(&mut self) -> Option<String> {
+        match self.inner.raw_metadata_json() {
+            Ok(json) => json,
+            Err(err) => {
+                warn!("Failed to decrypt metadata: {}", err);
+                None
+            },
+        }
+    }
+
+    pub fn extract_cover(&mut self) -> Option<Cover> {
+        match self.inner.read_header(false) {
+            Ok(header) => header.image.map(Cover::from),
+            Err(err) => {
+                warn!("Failed to extract cover: {}", err);
+                None
+            },
         }
     }
 }
 
-struct NcmDecoder {
-    data: Cursor<Vec<u8>>,
+/// internals for people working on the format, only with the `debug` feature
+#[cfg(feature = "debug")]
+#[wasm_bindgen]
+impl NcmDump {
+    /// `{ aes_key, key_box }` as `Uint8Array`s: the decrypted key block with its
+    /// `neteasecloudmusic` prefix and the 256 byte RC4 key box; `undefined` when the header
+    /// can't be read
+    pub fn debug_key_material(&mut self) -> Option<js_sys::Object> {
+        match self.inner.key_material() {
+            Ok((aes_key, key_box)) => {
+                let material = js_sys::Object::new();
+                let _ = js_sys::Reflect::set(&material, &"aes_key".into(), &js_sys::Uint8Array::from(aes_key.as_slice()));
+                let _ = js_sys::Reflect::set(&material, &"key_box".into(), &js_sys::Uint8Array::from(&key_box[..]));
+                Some(material)
+            },
+            Err(err) => {
+                warn!("Failed to read key material: {}", err);
+                None
+            },
+        }
+    }
+
+    /// `{ version, reserved }`, the two bytes after the magic header as numbers, `01` and
+    /// `70` hex in NetEase's files; `undefined` when the header can't be read
+    pub fn debug_format_version(&mut self) -> Option<js_sys::Object> {
+        match self.inner.format_version() {
+            Ok(version) => {
+                let object = js_sys::Object::new();
+                let _ = js_sys::Reflect::set(&object, &"version".into(), &version.version.into());
+                let _ = js_sys::Reflect::set(&object, &"reserved".into(), &version.reserved.into());
+                Some(object)
+            },
+            Err(err) => {
+                warn!("Failed to read format version: {}", err);
+                None
+            },
+        }
+    }
+}
+
+pub(crate) fn progress_callback(on_progress: &Option<js_sys::Function>) -> impl FnMut(u64, u64) + '_ {
+    move |done, total| {
+        if let Some(callback) = on_progress {
+            if let Err(err) = callback.call2(&JsValue::NULL, &JsValue::from(done as f64), &JsValue::from(total as f64)) {
+                warn!("Progress callback failed: {:?}", err);
+            }
+        }
+    }
+}
+
+/// resolves on a later turn of the event loop, so the page can render and handle input
+async fn yield_to_event_loop() {
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let set_timeout = js_sys::Reflect::get(&js_sys::global(), &"setTimeout".into())
+            .ok()
+            .and_then(|set_timeout| set_timeout.dyn_into::<js_sys::Function>().ok());
+        let _ = match set_timeout {
+            Some(set_timeout) => set_timeout.call2(&JsValue::NULL, &resolve, &JsValue::from(0)),
+            None => resolve.call0(&JsValue::NULL),
+        };
+    });
+    let _ = JsFuture::from(promise).await;
 }
 
-impl NcmDecoder {
-    fn new(data: Vec<u8>) -> Self {
+/// unknown format versions are decoded with a warning, and refused in strict mode
+fn check_version(header: &Header, options: &DumpOptions) -> DumpResult<()> {
+    if options.strict && !KNOWN_VERSIONS.contains(&header.version) {
+        return Err(DumpError::UnsupportedVersion(header.version.as_u16()));
+    }
+    Ok(())
+}
+
+/// drop the metadata unless `options` asks for it, in strict mode it has to be there
+fn filter_metadata(header: Header, options: &DumpOptions) -> DumpResult<Header> {
+    let metadata = header.metadata.filter(|_| options.parse_metadata);
+    if options.strict && options.parse_metadata && metadata.is_none() {
+        return Err(DumpError::MissingMetadata);
+    }
+    Ok(Header { metadata, ..header })
+}
+
+/// `should_cancel` for the decoder, a callback that throws doesn't cancel
+pub(crate) fn cancel_callback(should_cancel: &js_sys::Function) -> impl FnMut() -> bool + '_ {
+    move || match should_cancel.call0(&JsValue::NULL) {
+        Ok(cancel) => cancel.is_truthy(),
+        Err(err) => {
+            warn!("Cancel callback failed: {:?}", err);
+            false
+        },
+    }
+}
+
+/// milliseconds spent in each step of a dump, see `DumpOptions::set_collect_timings`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timings {
+    /// magic header, version and key
+    pub header: f64,
+    pub metadata: f64,
+    pub image: f64,
+    pub audio: f64,
+    pub tagging: f64,
+}
+
+/// `performance.now()` where there is one, it has sub-millisecond resolution
+#[cfg(target_arch = "wasm32")]
+fn now_ms() -> f64 {
+    let performance = js_sys::Reflect::get(&js_sys::global(), &"performance".into()).unwrap_or(JsValue::UNDEFINED);
+    js_sys::Reflect::get(&performance, &"now".into())
+        .ok()
+        .and_then(|now| now.dyn_into::<js_sys::Function>().ok())
+        .and_then(|now| now.call0(&performance).ok())
+        .and_then(|now| now.as_f64())
+        .unwrap_or_else(js_sys::Date::now)
+}
+
+/// js imports panic outside of wasm, natively the time since the first call is used instead
+#[cfg(not(target_arch = "wasm32"))]
+fn now_ms() -> f64 {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_secs_f64() * 1000.0
+}
+
+pub(crate) struct NcmDecoder<T: AsRef<[u8]> + Default> {
+    data: Cursor<T>,
+    core_key: [u8; 16],
+    modify_key: [u8; 16],
+    /// warnings of the current dump, returned to the caller along with the output
+    warnings: Vec<String>,
+    /// allocation the next `read_audio` decodes into, see `dump_into`
+    audio_buffer: Vec<u8>,
+    /// where the ncm file starts within `data`
+    start: u64,
+    /// set when `start` was given, the magic header then has to be right there instead of
+    /// somewhere in the first `MAGIC_SEARCH_WINDOW` bytes
+    exact_start: bool,
+    collect_timings: bool,
+    /// whether `source_hash` and the audio hash are computed, see `DumpOptions::set_compute_hashes`
+    compute_hashes: bool,
+    timings: Timings,
+    /// see `releasing_input`
+    release_input: bool,
+}
+
+impl<T: AsRef<[u8]> + Default> NcmDecoder<T> {
+    pub(crate) fn new(data: T) -> Self {
+        Self::with_keys(data, CORE_KEY, MODIFY_KEY)
+    }
+
+    /// decoder for clients that use the same container with their own AES keys
+    pub(crate) fn with_keys(data: T, core_key: [u8; 16], modify_key: [u8; 16]) -> Self {
         Self {
             data: Cursor::new(data),
+            core_key,
+            modify_key,
+            warnings: vec![],
+            audio_buffer: vec![],
+            start: 0,
+            exact_start: false,
+            collect_timings: false,
+            compute_hashes: false,
+            timings: Timings::default(),
+            release_input: false,
         }
     }
 
-    fn dump(&mut self) -> DumpResult<(Vec<u8>, String, String)> {
-        self.check_format()?;
-        self.skip(2)?;
+    /// decoder for an ncm file that starts exactly `start` bytes into `data`
+    pub(crate) fn starting_at(self, start: u64) -> Self {
+        Self {
+            start,
+            exact_start: true,
+            ..self
+        }
+    }
+
+    /// free the input as soon as the audio is decrypted instead of when the decoder is dropped,
+    /// for one-shot dumps; the decoder can't read the file again afterwards
+    pub(crate) fn releasing_input(self) -> Self {
+        Self {
+            release_input: true,
+            ..self
+        }
+    }
+
+    pub(crate) fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    pub(crate) fn dump(&mut self, options: &DumpOptions, progress: &mut dyn FnMut(u64, u64)) -> DumpResult<DecodedNcm> {
+        let mut audio = vec![];
+        let decoded = self.dump_into(options, &mut audio, progress)?;
+        Ok(DecodedNcm { audio, ..decoded })
+    }
 
-        let key_box = build_key_box(&self.read_aes_key()?[17..]);
-        let metadata = self.read_metadata()?;
+    /// like `dump`, the audio is written to `out` instead of `DecodedNcm::audio`, which is
+    /// left empty. `out` is cleared first and its allocation reused
+    pub(crate) fn dump_into(&mut self, options: &DumpOptions, out: &mut Vec<u8>, progress: &mut dyn FnMut(u64, u64)) -> DumpResult<DecodedNcm> {
+        self.audio_buffer = std::mem::take(out);
+        let result = self.read_header_for(options)
+            .and_then(|header| self.dump_audio(header, options, progress));
+        match result {
+            Ok(mut decoded) => {
+                *out = std::mem::take(&mut decoded.audio);
+                Ok(decoded)
+            },
+            Err(err) => {
+                // hand the allocation back if the audio wasn't read yet
+                *out = std::mem::take(&mut self.audio_buffer);
+                out.clear();
+                Err(err)
+            },
+        }
+    }
+
+    /// decode and tag the audio at the cursor with a `header` that was read before `options`
+    /// were known, for the two-step `parse_header` / `decode_audio_now`
+    fn dump_parsed(&mut self, header: Header, options: &DumpOptions, progress: &mut dyn FnMut(u64, u64)) -> DumpResult<DecodedNcm> {
+        self.use_options(options);
+        check_version(&header, options)?;
+        self.dump_audio(header, options, progress)
+    }
+
+    /// decode and tag the audio that starts at the cursor, `header` is the one read before it
+    fn dump_audio(&mut self, header: Header, options: &DumpOptions, progress: &mut dyn FnMut(u64, u64)) -> DumpResult<DecodedNcm> {
+        let header = filter_metadata(header, options)?;
+        let audio = self.read_audio(&header.key_box, options.audio_chunk_size, u64::MAX, progress)?;
+        self.finish_audio(header, audio, options)
+    }
+
+    /// like `dump`, returning to the event loop about once a frame while the audio is decrypted
+    /// so a page stays responsive
+    pub(crate) async fn dump_async(&mut self, options: &DumpOptions, progress: &mut dyn FnMut(u64, u64)) -> DumpResult<DecodedNcm> {
+        let header = self.read_header_for(options)?;
+        let header = filter_metadata(header, options)?;
+        let mut reader = self.start_audio(&header.key_box, options.audio_chunk_size, u64::MAX)?;
+        let mut last_yield = now_ms();
+        while self.timed(|timings| &mut timings.audio, |this| this.read_audio_chunk(&mut reader))? {
+            progress(reader.read, reader.len);
+            if now_ms() - last_yield >= YIELD_INTERVAL_MS {
+                yield_to_event_loop().await;
+                last_yield = now_ms();
+            }
+        }
+        let audio = reader.finish();
+        self.finish_audio(header, audio, options)
+    }
+
+    /// like `dump`, `should_cancel` is asked after every decrypted chunk and stops the dump with
+    /// `DumpError::Cancelled` once it returns true
+    pub(crate) fn dump_cancellable(&mut self, options: &DumpOptions, progress: &mut dyn FnMut(u64, u64), should_cancel: &mut dyn FnMut() -> bool) -> DumpResult<DecodedNcm> {
+        let header = self.read_header_for(options)?;
+        let header = filter_metadata(header, options)?;
+        let mut reader = self.start_audio(&header.key_box, options.audio_chunk_size, u64::MAX)?;
+        while self.timed(|timings| &mut timings.audio, |this| this.read_audio_chunk(&mut reader))? {
+            progress(reader.read, reader.len);
+            if should_cancel() {
+                return Err(DumpError::Cancelled);
+            }
+        }
+        let audio = reader.finish();
+        self.finish_audio(header, audio, options)
+    }
+
+    /// check and tag the decrypted `audio` of `header`
+    fn finish_audio(&mut self, header: Header, #[cfg_attr(not(feature = "tagging"), allow(unused_mut))] mut audio: Audio, options: &DumpOptions) -> DumpResult<DecodedNcm> {
+        let Header { version, metadata, image, .. } = header;
+        let audio_size = audio.data.len() as u64;
+        let format_mismatch = self.check_declared_format(metadata.as_ref(), &audio.format, options.strict)?;
+        let input_size = self.input_size();
+        let source_hash = self.source_hash();
+        if self.release_input {
+            // otherwise the input, the decrypted audio and the tagged copy are all alive at
+            // once while tagging, which is the peak memory use of a dump
+            *self.data.get_mut() = T::default();
+            self.data.set_position(0);
+        }
+
+        #[cfg(feature = "tagging")]
+        let (embedded, tag_error) = self.timed(|timings| &mut timings.tagging, |this| {
+            tagging::tag_audio(&mut audio, metadata.as_ref(), image.as_ref(), options, &mut this.warnings)
+        });
+        #[cfg(not(feature = "tagging"))]
+        let (embedded, tag_error) = (Embedded::default(), None);
+
+        Ok(DecodedNcm {
+            tagged: embedded.tagged,
+            has_metadata: embedded.metadata,
+            has_cover: embedded.cover,
+            tag_error,
+            format_mismatch,
+            audio_size,
+            timings: self.collect_timings.then_some(self.timings),
+            input_size,
+            source_hash,
+            warnings: self.take_warnings(),
+            ..DecodedNcm::new(audio, version, metadata, image)
+        })
+    }
+
+    /// decrypt the audio and return it byte for byte, no tags are read or written
+    fn dump_untagged(&mut self, parse_metadata: bool, progress: &mut dyn FnMut(u64, u64)) -> DumpResult<DecodedNcm> {
+        let header = self.read_header(parse_metadata)?;
+        let audio = self.read_audio(&header.key_box, DEFAULT_AUDIO_CHUNK_SIZE, u64::MAX, progress)?;
+        self.finish_untagged(header, audio)
+    }
+
+    /// check the decrypted `audio` of `header` without tagging it
+    pub(crate) fn finish_untagged(&mut self, header: Header, audio: Audio) -> DumpResult<DecodedNcm> {
+        let Header { version, metadata, image, .. } = header;
+        let format_mismatch = self.check_declared_format(metadata.as_ref(), &audio.format, false)?;
+        Ok(DecodedNcm {
+            format_mismatch,
+            input_size: self.input_size(),
+            source_hash: self.source_hash(),
+            warnings: self.take_warnings(),
+            ..DecodedNcm::new(audio, version, metadata, image)
+        })
+    }
+
+    /// untagged audio cut to at most `max_bytes`; mp3 is cut at a frame boundary so the last
+    /// frame isn't partial, other formats are simply truncated
+    fn dump_preview(&mut self, max_bytes: u64) -> DumpResult<DecodedNcm> {
+        let header = self.read_header(true)?;
+        let mut audio = self.read_audio(&header.key_box, DEFAULT_AUDIO_CHUNK_SIZE, max_bytes, &mut |_, _| {})?;
+        let truncated = audio.data.len() as u64 >= max_bytes;
+        if truncated && matches!(audio.format, AudioFileType::Mp3) {
+            let end = mp3_frame_boundary(&audio.data);
+            if end < audio.data.len() {
+                audio.data.truncate(end);
+                audio.size = end as u64;
+                if self.compute_hashes {
+                    let mut hasher = Sha256::new();
+                    hasher.update(&audio.data);
+                    audio.hash = hex_digest(hasher);
+                }
+            }
+        }
+        self.finish_untagged(header, audio)
+    }
+
+    /// whether the format named in the metadata disagrees with the detected one, a hint that
+    /// the file is corrupt; an error in strict mode
+    fn check_declared_format(&mut self, metadata: Option<&Metadata>, detected: &AudioFileType, strict: bool) -> DumpResult<bool> {
+        let declared = match mismatched_format(metadata, detected) {
+            Some(declared) => declared,
+            None => return Ok(false),
+        };
+        if strict {
+            return Err(DumpError::FormatMismatch { declared: declared.to_string(), detected: detected.name().to_string() });
+        }
+        record_warning(&mut self.warnings, format!("Metadata says the audio is {} but it was detected as {}", declared, detected.name()));
+        Ok(true)
+    }
+
+    /// read everything before the audio stream, leaving the cursor at the start of it
+    pub(crate) fn read_header(&mut self, parse_metadata: bool) -> DumpResult<Header> {
+        self.data.set_position(self.start);
+        self.warnings.clear();
+        self.timings = Timings::default();
+        let (version, key_box) = self.timed(|timings| &mut timings.header, |this| -> DumpResult<_> {
+            this.check_format()?;
+            let version = this.read_version()?;
+
+            let aes_key = this.read_aes_key()?;
+            Ok((version, build_key_box(&aes_key[KEY_PREFIX.len()..])?))
+        })?;
+        let metadata = self.timed(|timings| &mut timings.metadata, |this| {
+            if parse_metadata {
+                this.read_metadata()
+            } else {
+                let meta_len = this.data.read_le_u32()?;
+                this.skip(meta_len as i64)?;
+                Ok(None)
+            }
+        })?;
 
         self.skip(9)?;
 
-        let image = self.read_image()?;
-        let mut audio = self.read_audio(&key_box)?;
+        let image = self.timed(|timings| &mut timings.image, |this| this.read_image())?;
+        Ok(Header { version, key_box, metadata, image })
+    }
+
+    /// the header for a dump with `options`, timings are collected from here on if asked for
+    fn read_header_for(&mut self, options: &DumpOptions) -> DumpResult<Header> {
+        self.use_options(options);
+        let header = self.read_header(options.parse_metadata)?;
+        check_version(&header, options)?;
+        Ok(header)
+    }
+
+    /// the options that change how a dump is read rather than what it writes, shared by
+    /// every path that takes `DumpOptions`
+    fn use_options(&mut self, options: &DumpOptions) {
+        self.collect_timings = options.collect_timings;
+        self.compute_hashes = options.compute_hashes;
+    }
+
+    /// run `step`, adding the time it took to the `field` of the timings when they're collected
+    fn timed<R>(&mut self, field: fn(&mut Timings) -> &mut f64, step: impl FnOnce(&mut Self) -> R) -> R {
+        if !self.collect_timings {
+            return step(self);
+        }
+        let start = now_ms();
+        let result = step(self);
+        *field(&mut self.timings) += now_ms() - start;
+        result
+    }
+
+    /// check magic header, it is looked for from `start` on unless `start` was given
+    fn check_format(&mut self) -> DumpResult<()> {
+        let data = self.data.get_ref().as_ref().get(self.start as usize..).unwrap_or_default();
+        let offset = if self.exact_start {
+            data.starts_with(&MAGIC_HEADER).then_some(0)
+        } else {
+            find_magic_header(data)
+        };
+        let offset = offset
+            .ok_or_else(|| match foreign_format(data) {
+                Some(format) => DumpError::WrongFormat(format.to_string()),
+                None => DumpError::NotNcmFormat(data[..data.len().min(MAGIC_HEADER.len())].to_vec()),
+            })?;
+        if offset > 0 {
+            record_warning(&mut self.warnings, format!("Skipping {} bytes before the magic header", offset));
+        }
+        self.data.set_position(self.start + (offset + MAGIC_HEADER.len()) as u64);
+        Ok(())
+    }
+
+    /// the version bytes, anything but `01 70` is decoded the usual way with a warning
+    fn read_version(&mut self) -> DumpResult<FormatVersion> {
+        let mut buf = [0; 2];
+        self.data.read_exact(&mut buf)?;
+        let version = FormatVersion { version: buf[0], reserved: buf[1] };
+        if version != NCM_VERSION {
+            record_warning(&mut self.warnings, format!(
+                "Format version bytes are {:02x} {:02x} instead of 01 70 (version {}, reserved byte {:#04x}), the file was probably written by another tool",
+                buf[0], buf[1], version.version, version.reserved,
+            ));
+        }
+        Ok(version)
+    }
+
+    /// the decrypted key block, checked to start with `neteasecloudmusic`
+    fn read_aes_key(&mut self) -> DumpResult<Vec<u8>> {
+        let key_len = self.data.read_le_u32()?;
+        let mut key_data = self.read_bytes(key_len)?;
+
+        key_data.iter_mut().for_each(|b| *b ^= 0x64);
+
+        let aes_key = aes_decrypt(&mut key_data, &self.core_key).map_err(DumpError::KeyDecryptFailed)?;
+        if !aes_key.starts_with(KEY_PREFIX) {
+            return Err(DumpError::KeyDecryptFailed("decrypted key doesn't start with `neteasecloudmusic`".to_string()));
+        }
+        Ok(aes_key)
+    }
+
+    fn read_metadata(&mut self) -> DumpResult<Option<Metadata>> {
+        let metadata_str = match self.read_metadata_json()? {
+            Some(metadata_str) => metadata_str,
+            None => return Ok(None),
+        };
+        // some files append a `, "musicEx": {...}` section after the object, only the first
+        // value is parsed so it doesn't fail as trailing data
+        let mut values = serde_json::Deserializer::from_str(&metadata_str).into_iter::<Metadata>();
+        let metadata = values.next().ok_or(DumpError::MetadataTooShort)??;
+        let trailing = metadata_str[values.byte_offset()..].trim();
+        if !trailing.is_empty() {
+            record_warning(&mut self.warnings, format!("Ignoring {} bytes after the metadata JSON", trailing.len()));
+        }
+        Ok(Some(metadata))
+    }
+
+    /// the decrypted metadata block without the `music:` prefix, not parsed yet
+    fn read_metadata_json(&mut self) -> DumpResult<Option<String>> {
+        let meta_len = self.data.read_le_u32()?;
+        if meta_len == 0 {
+            record_warning(&mut self.warnings, "No metadata information found in file".to_string());
+            return Ok(None);
+        }
+        let mut meta_data = self.read_bytes(meta_len)?;
+
+        meta_data.iter_mut().for_each(|b| *b ^= 0x63);
+
+        // skip `163 key(Don't modify):`, the colon can't appear in the base64 payload
+        let separator = match meta_data.iter().position(|&b| b == b':') {
+            Some(separator) => separator,
+            // too short to hold the prefix, the block was cut rather than laid out differently
+            None if meta_data.len() < METADATA_PREFIX.len() => return Err(DumpError::MetadataTooShort),
+            None => return Err(DumpError::MetadataParse("missing `163 key` prefix".to_string())),
+        };
+        let payload = meta_data[separator + 1..].iter()
+            .copied()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect::<Vec<_>>();
+        if payload.is_empty() {
+            return Err(DumpError::MetadataTooShort);
+        }
+        let mut modify_data = base64::decode(&payload)?;
+        let decrypt_data = aes_decrypt(&mut modify_data, &self.modify_key).map_err(DumpError::MetadataDecryptFailed)?;
+
+        // some clients compress either the whole block or just the json after `music:`
+        let decrypt_data = inflate(&decrypt_data)?.unwrap_or(decrypt_data);
+        // skip `music:`
+        if decrypt_data.len() < 6 {
+            return Err(DumpError::MetadataTooShort);
+        }
+        let json = inflate(&decrypt_data[6..])?;
+        let json = json.as_deref().unwrap_or(&decrypt_data[6..]);
+        Ok(Some(String::from_utf8_lossy(json).into_owned()))
+    }
+
+    /// the metadata JSON as stored, for files whose metadata doesn't parse; the key block is
+    /// skipped without decrypting it
+    fn raw_metadata_json(&mut self) -> DumpResult<Option<String>> {
+        self.data.set_position(self.start);
+        self.warnings.clear();
+        self.check_format()?;
+        self.read_version()?;
+        let key_len = self.data.read_le_u32()?;
+        self.skip(key_len as i64)?;
+        self.read_metadata_json()
+    }
+
+    fn read_image(&mut self) -> DumpResult<Option<Image>> {
+        let image_len = self.data.read_le_u32()?;
+        if image_len == 0 {
+            record_warning(&mut self.warnings, "No image found in file".to_string());
+            return Ok(None);
+        }
+        let image_data = self.read_bytes(image_len)?;
+        let filetype = ImageFileType::from_header_data(&image_data);
+        if let ImageFileType::Unknown = filetype {
+            record_warning(&mut self.warnings, "Unknown image format, keeping raw bytes".to_string());
+        }
+        Ok(Some(Image {
+            format: filetype,
+            data: image_data,
+        }))
+    }
+
+    /// decrypt at most `max_len` bytes of audio, `progress` is called with
+    /// `(bytes_done, bytes_total)` after each decoded chunk
+    fn read_audio(&mut self, key_box: &[u8], chunk_size: usize, max_len: u64, progress: &mut dyn FnMut(u64, u64)) -> DumpResult<Audio> {
+        let mut reader = self.start_audio(key_box, chunk_size, max_len)?;
+        while self.timed(|timings| &mut timings.audio, |this| this.read_audio_chunk(&mut reader))? {
+            progress(reader.read, reader.len);
+        }
+        Ok(reader.finish())
+    }
+
+    /// detect the format of the audio at the cursor and get ready to decrypt at most `max_len`
+    /// bytes of it with `read_audio_chunk`
+    pub(crate) fn start_audio<'a>(&mut self, key_box: &'a [u8], chunk_size: usize, max_len: u64) -> DumpResult<AudioReader<'a>> {
+        let cur_offset = self.skip(0)?;
+        let eof_offset = self.data.seek(SeekFrom::End(0))?;
+
+        let audio_len = (eof_offset - cur_offset).min(max_len);
+        let mut audio_data = std::mem::take(&mut self.audio_buffer);
+        audio_data.clear();
+        audio_data.reserve(audio_len as usize);
+
+        self.data.seek(SeekFrom::Start(cur_offset))?;
+        let format = self.detect_format(key_box)?;
+
+        Ok(AudioReader {
+            key_box,
+            buf: vec![0u8; chunk_size.max(1)],
+            data: audio_data,
+            len: audio_len,
+            read: 0,
+            format,
+            hasher: self.compute_hashes.then(Sha256::new),
+        })
+    }
+
+    /// identify the audio at the cursor from a copy, the chunks read these bytes again from the
+    /// file so nothing decoded here ends up in the output
+    fn detect_format(&mut self, key_box: &[u8]) -> DumpResult<AudioFileType> {
+        let start = self.skip(0)?;
+        let mut header = vec![0u8; AUDIO_HEADER_LEN];
+        let header_len = self.data.read(&mut header)?;
+        if header_len == 0 {
+            return Err(DumpError::NoAudioData);
+        }
+        header.truncate(header_len);
+        decode_audio(&mut header, 0, key_box);
+        let format = match detect_audio_format(&header) {
+            Some(format) => format,
+            None => {
+                // read on to the bytes after the ID3v2 tag, the file may end before them
+                let needed = id3v2_tag_len(&header) + 4 - header_len;
+                self.data.by_ref().take(needed as u64).read_to_end(&mut header)?;
+                decode_audio(&mut header[header_len..], header_len, key_box);
+                detect_audio_format(&header).unwrap_or_else(|| AudioFileType::from_header_data(&header))
+            },
+        };
+        self.data.seek(SeekFrom::Start(start))?;
+        Ok(format)
+    }
+
+    /// decrypt the next chunk into `reader`, false once all of the audio has been read
+    fn read_audio_chunk(&mut self, reader: &mut AudioReader) -> DumpResult<bool> {
+        let chunk = self.next_audio_chunk(reader)?;
+        if chunk.is_empty() {
+            return Ok(false);
+        }
+        let chunk_len = chunk.len();
+        reader.data.write_all(&reader.buf[..chunk_len])?;
+        Ok(true)
+    }
+
+    /// decrypt the next chunk without keeping it in `reader`, empty once all of the audio has
+    /// been read
+    pub(crate) fn next_audio_chunk<'r>(&mut self, reader: &'r mut AudioReader) -> DumpResult<&'r [u8]> {
+        let remaining = reader.len - reader.read;
+        let read_len = reader.buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        let read_size = self.data.read(&mut reader.buf[..read_len])?;
+        decode_audio(&mut reader.buf[..read_size], reader.read as usize, reader.key_box);
+        if let Some(hasher) = &mut reader.hasher {
+            hasher.update(&reader.buf[..read_size]);
+        }
+        reader.read += read_size as u64;
+        Ok(&reader.buf[..read_size])
+    }
+
+    /// read a length-prefixed block, refusing lengths that run past the end of the file
+    fn read_bytes(&mut self, len: u32) -> DumpResult<Vec<u8>> {
+        let remaining = (self.data.get_ref().as_ref().len() as u64).saturating_sub(self.data.position());
+        if len as u64 > remaining {
+            return Err(DumpError::TruncatedHeader);
+        }
+        let mut buf = vec![0; len as usize];
+        self.data.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn keystream_at(&mut self, offset: usize, len: usize) -> DumpResult<Vec<u8>> {
+        let key_box = self.read_header(false)?.key_box;
+        // decoding zeros yields the keystream itself
+        let mut keystream = vec![0; len];
+        decode_audio(&mut keystream, offset, &key_box);
+        Ok(keystream)
+    }
+
+    /// the version bytes after the magic header, without reading any further
+    #[cfg(feature = "debug")]
+    fn format_version(&mut self) -> DumpResult<FormatVersion> {
+        self.data.set_position(self.start);
+        self.check_format()?;
+        self.read_version()
+    }
+
+    /// the decrypted key block, `neteasecloudmusic` prefix included, and the key box built
+    /// from the rest of it
+    #[cfg(feature = "debug")]
+    fn key_material(&mut self) -> DumpResult<(Vec<u8>, [u8; 256])> {
+        self.data.set_position(self.start);
+        self.check_format()?;
+        self.read_version()?;
+        let aes_key = self.read_aes_key()?;
+        let key_box = build_key_box(&aes_key[KEY_PREFIX.len()..])?;
+        Ok((aes_key, key_box))
+    }
+
+    /// detect the audio format from the first decrypted bytes, without the metadata or the
+    /// rest of the audio
+    fn peek_format(&mut self) -> DumpResult<AudioFileType> {
+        let key_box = self.read_header(false)?.key_box;
+        self.detect_format(&key_box)
+    }
+
+    fn input_size(&self) -> u64 {
+        self.data.get_ref().as_ref().len() as u64
+    }
+
+    /// empty unless hashes were asked for
+    fn source_hash(&self) -> String {
+        if !self.compute_hashes {
+            return String::new();
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(self.data.get_ref());
+        hex_digest(hasher)
+    }
+
+    pub(crate) fn position(&self) -> u64 {
+        self.data.position()
+    }
+
+    #[inline]
+    fn skip(&mut self, byte_num: i64) -> DumpResult<u64> {
+        Ok(self.data.seek(SeekFrom::Current(byte_num))?)
+    }
+}
+
+/// Cheap check that `data` looks like an ncm file: the magic header matches and the file is long
+/// enough to hold the key block and the metadata length. Nothing is decrypted.
+#[wasm_bindgen]
+pub fn is_ncm(data: &[u8]) -> bool {
+    let data = match find_magic_header(data) {
+        Some(offset) => &data[offset..],
+        None => return false,
+    };
+    if data.len() < 14 {
+        return false;
+    }
+    // in u64, a key length near u32::MAX would overflow a 32 bit usize
+    let key_len = u32::from_le_bytes([data[10], data[11], data[12], data[13]]) as u64;
+    data.len() as u64 >= 14 + key_len + 4
+}
+
+/// bytes `read_header` needs, from the block lengths alone without decrypting anything. While
+/// `data` ends before one of the lengths it is only the bytes needed to read that length;
+/// `None` when there is no magic header to start from
+pub(crate) fn header_len(data: &[u8]) -> Option<u64> {
+    let start = find_magic_header(data)?;
+    // magic and version, then the key and metadata blocks and the image block after a gap
+    let mut len = start as u64 + 10;
+    for &gap in [0, 0, 9].iter() {
+        len += gap;
+        let length_bytes = usize::try_from(len).ok().and_then(|at| data.get(at..at.checked_add(4)?));
+        match length_bytes {
+            Some(bytes) => len += 4 + u32::from_le_bytes(bytes.try_into().unwrap()) as u64,
+            None => return Some(len + 4),
+        }
+    }
+    Some(len)
+}
+
+/// offset of the magic header within the first `MAGIC_SEARCH_WINDOW` bytes
+fn find_magic_header(data: &[u8]) -> Option<usize> {
+    let window = &data[..data.len().min(MAGIC_SEARCH_WINDOW + MAGIC_HEADER.len())];
+    window.windows(MAGIC_HEADER.len()).position(|bytes| bytes == MAGIC_HEADER)
+}
+
+/// name of the format `data` is in when it is a known one other than ncm, so the error can
+/// say what the file is; nothing is decoded
+fn foreign_format(data: &[u8]) -> Option<&'static str> {
+    if let Some((_, name)) = FOREIGN_HEADERS.iter().find(|(header, _)| data.starts_with(header)) {
+        return Some(name);
+    }
+    // QQ Music files have no header, older ones end with a tag block
+    if data.ends_with(b"QTag") || data.ends_with(b"STag") {
+        return Some("QQ Music (mflac/mgg)");
+    }
+    match AudioFileType::from_header_data(data) {
+        AudioFileType::Unknown => None,
+        format => Some(format.name()),
+    }
+}
+
+/// Decrypt an ncm file and tag the audio with its metadata and cover.
+///
+/// This is the plain Rust entry point, the wasm `dump` functions are thin wrappers around it.
+pub fn decode_ncm(data: &[u8]) -> Result<DecodedNcm, DumpError> {
+    decode_ncm_with_keys(data, CORE_KEY, MODIFY_KEY)
+}
+
+/// Like `decode_ncm`, with `options` deciding what gets embedded; e.g. turn off
+/// `set_embed_metadata` to embed only the cover.
+pub fn decode_ncm_with_options(data: &[u8], options: &DumpOptions) -> Result<DecodedNcm, DumpError> {
+    NcmDecoder::new(data).dump(options, &mut |_, _| {})
+}
+
+/// Like `decode_ncm`, the audio is written to `out` and `DecodedNcm::audio` is left empty.
+///
+/// `out` is cleared first and keeps its allocation, so one buffer can be reused for a whole batch.
+pub fn dump_into(data: &[u8], out: &mut Vec<u8>) -> Result<DecodedNcm, DumpError> {
+    NcmDecoder::new(data).dump_into(&DumpOptions::default(), out, &mut |_, _| {})
+}
+
+/// Like `decode_ncm`, for files that share the ncm layout but encrypt the key and metadata
+/// blocks with other AES keys.
+pub fn decode_ncm_with_keys(data: &[u8], core_key: [u8; 16], modify_key: [u8; 16]) -> Result<DecodedNcm, DumpError> {
+    NcmDecoder::with_keys(data, core_key, modify_key).dump(&DumpOptions::default(), &mut |_, _| {})
+}
+
+pub struct DecodedNcm {
+    pub audio: Vec<u8>,
+    /// hex SHA-256 of the decrypted audio before tagging, equal for the same source audio
+    /// no matter which tags end up in `audio`; empty unless `DumpOptions::set_compute_hashes`
+    /// was set
+    pub audio_hash: String,
+    pub metadata: Option<Metadata>,
+    pub image: Option<Cover>,
+    pub extension: String,
+    pub format: OutputFormat,
+    /// format detected from the decrypted audio, `"unknown"` if it wasn't recognized
+    pub source_format: String,
+    /// MIME type of `audio`, `application/octet-stream` if the format wasn't recognized
+    pub mime_type: String,
+    /// bytes of decrypted audio before tagging
+    pub audio_size: u64,
+    /// how long each step took, only when `DumpOptions::set_collect_timings` was set
+    pub timings: Option<Timings>,
+    /// bytes of the ncm file
+    pub input_size: u64,
+    /// hex SHA-256 of the ncm file as it was read, for caching results by input; empty unless
+    /// `DumpOptions::set_compute_hashes` was set
+    pub source_hash: String,
+    /// the two bytes after the magic header, see `DumpOutput::format_version`
+    pub format_version: u16,
+    /// whether metadata or cover were embedded into `audio`
+    pub tagged: bool,
+    /// whether title, artists and the other metadata were embedded into `audio`
+    pub has_metadata: bool,
+    /// whether a cover was embedded into `audio`; false when the file has none, the options
+    /// left it out or the audio kept its own picture
+    pub has_cover: bool,
+    /// set when the format named in the metadata isn't `source_format`
+    pub format_mismatch: bool,
+    /// set when tagging failed and `audio` was returned untagged
+    pub tag_error: Option<DumpError>,
+    /// problems that didn't stop the dump, like a missing cover
+    pub warnings: Vec<String>,
+}
+
+impl DecodedNcm {
+    fn new(audio: Audio, version: FormatVersion, metadata: Option<Metadata>, image: Option<Image>) -> Self {
+        Self {
+            extension: audio.format.extension().to_string(),
+            format: OutputFormat::from(&audio.format),
+            source_format: audio.format.name().to_string(),
+            mime_type: audio.format.mime_type().to_string(),
+            audio_size: audio.size,
+            timings: None,
+            input_size: 0,
+            source_hash: String::new(),
+            audio: audio.data,
+            audio_hash: audio.hash,
+            metadata,
+            image: image.map(Cover::from),
+            format_version: version.as_u16(),
+            tagged: false,
+            has_metadata: false,
+            has_cover: false,
+            format_mismatch: false,
+            tag_error: None,
+            warnings: vec![],
+        }
+    }
+}
+
+/// source quality as far as the metadata tells it.
+///
+/// NetEase doesn't document the bits of `flag`, so none of them is named and the whole field
+/// is kept in `raw`; the named values come from the format and bitrate fields.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct QualityFlags {
+    /// FLAC source
+    pub(crate) lossless: bool,
+    /// 320 kbps or more, the highest lossy quality NetEase offers
+    pub(crate) high_bitrate: bool,
+    pub(crate) raw: u64,
+}
+
+impl From<&Metadata> for QualityFlags {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            lossless: metadata.format.eq_ignore_ascii_case("flac"),
+            high_bitrate: metadata.bitrate >= 320_000,
+            raw: metadata.flag,
+        }
+    }
+}
+
+/// what was written into the audio by `NcmDecoder::tag_audio`
+#[derive(Default)]
+pub(crate) struct Embedded {
+    pub(crate) tagged: bool,
+    pub(crate) metadata: bool,
+    pub(crate) cover: bool,
+}
+
+pub(crate) struct Audio {
+    pub(crate) format: AudioFileType,
+    pub(crate) data: Vec<u8>,
+    /// bytes decrypted, also counted when `data` wasn't kept
+    size: u64,
+    /// hex SHA-256 of `data` as decrypted, before any tags are written; empty unless hashes
+    /// were asked for
+    hash: String,
+}
+
+/// audio decrypted so far, filled by `NcmDecoder::read_audio_chunk`
+pub(crate) struct AudioReader<'a> {
+    key_box: &'a [u8],
+    buf: Vec<u8>,
+    data: Vec<u8>,
+    /// bytes that will be read in total
+    len: u64,
+    /// bytes read so far
+    read: u64,
+    format: AudioFileType,
+    hasher: Option<Sha256>,
+}
+
+impl AudioReader<'_> {
+    pub(crate) fn finish(self) -> Audio {
+        Audio {
+            format: self.format,
+            hash: self.hasher.map(hex_digest).unwrap_or_default(),
+            size: self.read,
+            data: self.data,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Image {
+    pub(crate) format: ImageFileType,
+    pub(crate) data: Vec<u8>,
+}
+
+#[wasm_bindgen]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    #[serde(default)]
+    format: String,
+    #[serde(default)]
+    pub(crate) music_id: u64,
+    pub(crate) music_name: String,
+    #[serde(deserialize_with = "deserialize_artists")]
+    pub(crate) artist: Vec<(String, u64)>,
+    pub(crate) album: String,
+    #[serde(default)]
+    pub(crate) album_id: u64,
+    #[serde(default)]
+    album_pic_doc_id: u128,
+    #[serde(default)]
+    album_pic: String,
+    #[serde(default)]
+    pub(crate) mv_id: u64,
+    #[serde(default)]
+    flag: u64,
+    #[serde(default)]
+    bitrate: u64,
+    #[serde(default)]
+    duration: u64,
+    #[serde(default)]
+    pub(crate) trans_names: Vec<String>,
+    #[serde(default)]
+    album_artist: Option<String>,
+    #[serde(default, alias = "no", deserialize_with = "deserialize_position")]
+    pub(crate) track_number: Option<u32>,
+    #[serde(default, alias = "cd", deserialize_with = "deserialize_position")]
+    pub(crate) disc_number: Option<u32>,
+    /// release date as milliseconds since the epoch
+    #[serde(default)]
+    publish_time: Option<i64>,
+    #[serde(default)]
+    year: Option<i32>,
+    #[serde(default)]
+    pub(crate) genre: Option<String>,
+    #[serde(default, alias = "description")]
+    comment: Option<String>,
+    /// only some files carry lyrics, usually in LRC format
+    #[serde(default, alias = "lyric")]
+    lyrics: Option<String>,
+}
+
+impl Metadata {
+    /// the explicit year, otherwise the year of `publish_time`
+    pub(crate) fn release_year(&self) -> Option<i32> {
+        self.year
+            .or_else(|| self.publish_time.filter(|&ms| ms > 0).map(year_from_timestamp))
+            .filter(|&year| year > 0)
+    }
+}
+
+/// UTC year of a timestamp in milliseconds, using the days-to-civil conversion from
+/// http://howardhinnant.github.io/date_algorithms.html
+fn year_from_timestamp(ms: i64) -> i32 {
+    let days = ms.div_euclid(86_400_000) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    // the year starts in March here, January and February belong to the next one
+    let year = year_of_era + era * 400 + if month_index >= 10 { 1 } else { 0 };
+    year as i32
+}
+
+/// track/disc positions show up both as numbers and as strings like `"01"`
+fn deserialize_position<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Position {
+        Number(u32),
+        Text(String),
+    }
+
+    Ok(match Option::<Position>::deserialize(deserializer)? {
+        Some(Position::Number(n)) => Some(n),
+        Some(Position::Text(text)) => text.trim().parse().ok(),
+        None => None,
+    }.filter(|n| *n > 0))
+}
+
+/// artists are usually `[name, id]` pairs, but also show up as bare names or `{ name, id }`
+/// objects; a missing id is 0
+fn deserialize_artists<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, u64)>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ArtistEntry {
+        Pair(String, u64),
+        Name(String),
+        Object {
+            name: String,
+            #[serde(default)]
+            id: u64,
+        },
+    }
+
+    Ok(Vec::<ArtistEntry>::deserialize(deserializer)?
+        .into_iter()
+        .map(|entry| match entry {
+            ArtistEntry::Pair(name, id) | ArtistEntry::Object { name, id } => (name, id),
+            ArtistEntry::Name(name) => (name, 0),
+        })
+        .collect())
+}
+
+#[wasm_bindgen]
+impl Metadata {
+    pub fn music_id(&self) -> u64 {
+        self.music_id
+    }
 
-        let extension: String;
+    pub fn music_name(&self) -> String {
+        self.music_name.clone()
+    }
 
-        match audio.format {
-            AudioFileType::Flac => {
-                add_flac_metadata(&mut audio, &image, &metadata)?;
-                extension = "flac".to_string();
-            },
-            AudioFileType::Mp3 => {
-                add_mp3_metadata(&mut audio, &image, &metadata)?;
-                extension = "mp3".to_string();
-            },
-        }
+    pub fn album(&self) -> String {
+        self.album.clone()
+    }
 
-        Ok((audio.data, serde_json::to_string(&metadata).map_err(err_to_string)?, extension))
+    pub fn artists(&self) -> Vec<Artist> {
+        self.artist.iter()
+            .map(|(name, id)| Artist { name: name.clone(), id: *id })
+            .collect()
     }
 
-    /// check magic header
-    fn check_format(&mut self) -> DumpResult<()> {
-        let mut buf = [0; 8];
-        let read_size = self.data.read(&mut buf).map_err(err_to_string)?;
-        if read_size != 8 || buf != MAGIC_HEADER {
-            Err("This file is not in ncm format".to_string())
-        } else {
-            Ok(())
-        }
+    pub fn duration(&self) -> u64 {
+        self.duration
     }
 
-    fn read_aes_key(&mut self) -> DumpResult<Vec<u8>> {
-        let key_len = self.data.read_le_u32().map_err(err_to_string)?;
-        let mut key_data = vec![0; key_len as usize];
-        self.data.read_exact(&mut key_data).map_err(err_to_string)?;
+    pub fn bitrate(&self) -> u64 {
+        self.bitrate
+    }
 
-        key_data.iter_mut().for_each(|b| *b ^= 0x64);
+    /// the dedicated album artist if present, otherwise the first track artist
+    pub fn album_artist(&self) -> Option<String> {
+        self.album_artist.clone()
+            .or_else(|| self.artist.first().map(|a| a.0.clone()))
+    }
 
-        aes_decrypt(&mut key_data, &CORE_KEY)
+    pub fn track_number(&self) -> Option<u32> {
+        self.track_number
     }
 
-    fn read_metadata(&mut self) -> DumpResult<Option<Metadata>> {
-        let meta_len = self.data.read_le_u32().map_err(err_to_string)?;
-        if meta_len == 0 {
-            warn!("No metadata information found in file");
-            return Ok(None);
-        }
-        let mut meta_data = vec![0; meta_len as usize];
-        self.data.read_exact(&mut meta_data).map_err(err_to_string)?;
+    pub fn disc_number(&self) -> Option<u32> {
+        self.disc_number
+    }
 
-        meta_data.iter_mut().for_each(|b| *b ^= 0x63);
+    /// release year, taken from `year` or derived from `publishTime`
+    pub fn year(&self) -> Option<i32> {
+        self.release_year()
+    }
 
-        // skip `163 key` ...
-        let mut modify_data = base64::decode(&meta_data[22..]).map_err(err_to_string)?;
-        let decrypt_data = aes_decrypt(&mut modify_data, &MODIFY_KEY)?;
+    pub fn genre(&self) -> Option<String> {
+        self.genre.clone()
+    }
 
-        // skip `music:`
-        let metadata_str = String::from_utf8_lossy(&decrypt_data[6..]);
-        let metadata = serde_json::from_str::<Metadata>(&metadata_str).map_err(err_to_string)?;
-        Ok(Some(metadata))
+    /// free text some tracks carry, `None` when missing or empty
+    pub fn comment(&self) -> Option<String> {
+        self.comment.clone().filter(|comment| !comment.is_empty())
     }
 
-    fn read_image(&mut self) -> DumpResult<Option<Image>> {
-        let image_len = self.data.read_le_u32().map_err(err_to_string)?;
-        if image_len == 0 {
-            warn!("No image found in file");
-            return Ok(None);
-        }
-        let mut image_data = vec![0; image_len as usize];
-        self.data.read_exact(&mut image_data).map_err(err_to_string)?;
-        let filetype = ImageFileType::from_header_data(&image_data[0..8]);
-        Ok(Some(Image {
-            format: filetype,
-            data: image_data,
-        }))
+    /// lyrics stored in the metadata, `None` when missing or empty
+    pub fn lyrics(&self) -> Option<String> {
+        self.lyrics.clone().filter(|lyrics| !lyrics.trim().is_empty())
     }
+}
 
-    fn read_audio(&mut self, key_box: &[u8]) -> DumpResult<Audio> {
-        let mut buf = [0u8; 0x8000];
+#[wasm_bindgen]
+pub struct Artist {
+    name: String,
+    id: u64,
+}
 
-        let cur_offset = self.skip(0)?;
-        let eof_offset = self.data.seek(SeekFrom::End(0)).map_err(err_to_string)?;
-
-        let audio_len = eof_offset - cur_offset;
-        let mut audio_data: Vec<u8> = Vec::with_capacity(audio_len as usize);
-
-        self.data.seek(SeekFrom::Start(cur_offset)).map_err(err_to_string)?;
-        // identify file type
-        self.data.read_exact(&mut buf[..4]).map_err(err_to_string)?;
-        self.skip(-4)?;
-        decode_audio(&mut buf, 4, key_box);
-        let filetype = AudioFileType::from_header_data(&buf[0..4]);
-
-        loop {
-            let read_size = self.data.read(&mut buf).map_err(err_to_string)?;
-            if read_size == 0 {
-                break;
-            }
-            decode_audio(&mut buf, read_size, key_box);
-            audio_data.write_all(&buf[0..read_size]).map_err(err_to_string)?;
-        }
+#[wasm_bindgen]
+impl Artist {
+    pub fn name(&self) -> String {
+        self.name.clone()
+    }
 
-        Ok(Audio {
-            format: filetype,
-            data: audio_data,
-        })
+    pub fn id(&self) -> u64 {
+        self.id
     }
+}
 
-    #[inline]
-    fn skip(&mut self, byte_num: i64) -> DumpResult<u64> {
-        self.data.seek(SeekFrom::Current(byte_num)).map_err(err_to_string)
+/// the error says what went wrong, the callers add which block it was
+fn aes_decrypt(data: &mut [u8], key: &[u8]) -> Result<Vec<u8>, String> {
+    let cipher = Ecb::<Aes128, Pkcs7>::new_var(key, Default::default())
+        .map_err(|_| format!("invalid key length {}", key.len()))?;
+    // a wrong key shows up as bad padding, as does a block that isn't a multiple of 16 bytes
+    let len = data.len();
+    cipher.decrypt(data)
+        .map(|data| data.to_owned())
+        .map_err(|_| format!("bad padding in {} bytes, wrong key or corrupt data", len))
+}
+
+/// the format named in the metadata when it isn't the detected one, nothing to compare if
+/// either is unknown
+pub(crate) fn mismatched_format<'a>(metadata: Option<&'a Metadata>, detected: &AudioFileType) -> Option<&'a str> {
+    let declared = metadata.map(|metadata| metadata.format.as_str()).filter(|format| !format.is_empty())?;
+    if matches!(detected, AudioFileType::Unknown) || declared.eq_ignore_ascii_case(detected.name()) {
+        return None;
     }
+    Some(declared)
 }
 
-struct Audio {
-    format: AudioFileType,
-    data: Vec<u8>,
+/// log a warning and keep it for the output
+pub(crate) fn record_warning(warnings: &mut Vec<String>, message: String) {
+    warn!("{}", message);
+    warnings.push(message);
 }
 
-struct Image {
-    format: ImageFileType,
-    data: Vec<u8>,
+/// inflate gzip or zlib data, `None` if `data` doesn't start with either header
+fn inflate(data: &[u8]) -> DumpResult<Option<Vec<u8>>> {
+    let mut inflated = vec![];
+    match data {
+        [0x1F, 0x8B, ..] => flate2::read::GzDecoder::new(data).read_to_end(&mut inflated),
+        // zlib: deflate method and a header checksum that is a multiple of 31
+        [cmf, flg, ..] if cmf & 0x0F == 8 && (*cmf as u16 * 256 + *flg as u16).is_multiple_of(31) => {
+            flate2::read::ZlibDecoder::new(data).read_to_end(&mut inflated)
+        },
+        _ => return Ok(None),
+    }.map_err(|err| DumpError::MetadataParse(format!("failed to inflate metadata: {}", err)))?;
+    Ok(Some(inflated))
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct Metadata {
-    format: String,
-    music_id: u64,
-    music_name: String,
-    artist: Vec<(String, u64)>,
-    album: String,
-    album_id: u64,
-    album_pic_doc_id: u128,
-    album_pic: String,
-    mv_id: u64,
-    flag: u64,
-    bitrate: u64,
-    duration: u64,
-    trans_names: Vec<String>,
+pub(crate) fn hex_digest(hasher: Sha256) -> String {
+    hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
 }
 
-fn aes_decrypt(data: &mut [u8], key: &[u8]) -> DumpResult<Vec<u8>> {
-    let cipher = Ecb::<Aes128, Pkcs7>::new_var(key, Default::default()).map_err(err_to_string)?;
-    Ok(cipher.decrypt(data).map_err(err_to_string)?.to_owned())
+/// offset of the last mpeg frame header after any ID3 tag, so the data before it only holds
+/// complete frames; the whole length if no frame header is found
+fn mp3_frame_boundary(data: &[u8]) -> usize {
+    let frames_offset = id3v2_tag_len(data);
+    let is_frame_header = |header: &[u8]| matches!(header, [0xFF, b1, b2, _]
+        if b1 & 0xE0 == 0xE0
+            && (b1 >> 3) & 0b11 != 0b01
+            && (b1 >> 1) & 0b11 != 0
+            && b2 >> 4 != 0b1111
+            && b2 >> 4 != 0
+            && (b2 >> 2) & 0b11 != 0b11);
+    (frames_offset + 1..data.len().saturating_sub(3))
+        .rev()
+        .find(|&i| is_frame_header(&data[i..i + 4]))
+        .unwrap_or(data.len())
 }
 
-fn decode_audio(data: &mut [u8], read_size: usize, key_box: &[u8]) {
-    for i in 0..read_size {
-        let j = (i + 1) & 0xff;
-        data[i] ^= key_box[(key_box[j] as usize + key_box[(key_box[j] as usize + j) & 0xff] as usize) & 0xff];
+/// `offset` is the position of `data` within the audio stream, the keystream depends on it
+pub(crate) fn decode_audio(data: &mut [u8], offset: usize, key_box: &[u8]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        let j = (offset + i + 1) & 0xff;
+        *byte ^= key_box[(key_box[j] as usize + key_box[(key_box[j] as usize + j) & 0xff] as usize) & 0xff];
     }
 }
 
@@ -219,164 +1435,624 @@ const fn init_key_box() -> [u8; 256] {
     data
 }
 
-fn build_key_box(key_data: &[u8]) -> [u8; 256] {
-    let key_len = key_data.len();
+/// RC4 key scheduling: `j = j + box[i] + key[i % key.len()]` for every `i`, then swap
+/// `box[i]` and `box[j]`. All index math is done on `u8` so it wraps at 256 by construction.
+pub(crate) fn build_key_box(key_data: &[u8]) -> DumpResult<[u8; 256]> {
+    if key_data.is_empty() {
+        return Err(DumpError::AesError("empty RC4 key".to_string()));
+    }
     let mut key_box = init_key_box();
-    let mut last_byte = 0u8;
-    let mut key_offset = 0;
-    for i in 0..key_box.len() {
-        let c = (key_box[i] + last_byte + key_data[key_offset]) & 0xff;
-        key_offset += 1;
-        if key_offset >= key_len {
-            key_offset = 0;
-        }
-        key_box.swap(i, c as usize);
-        last_byte = c;
+    let mut j = 0u8;
+    for (i, &key_byte) in (0..key_box.len()).zip(key_data.iter().cycle()) {
+        j = j.wrapping_add(key_box[i]).wrapping_add(key_byte);
+        key_box.swap(i, j as usize);
     }
-    key_box
+    Ok(key_box)
+}
+
+pub(crate) enum AudioFileType {
+    Mp3,
+    Flac,
+    Ogg,
+    M4a,
+    Wav,
+    Unknown,
 }
 
-enum AudioFileType {
+/// format of the output audio, the typed counterpart of `DumpOutput::extension`
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
     Mp3,
     Flac,
+    Ogg,
+    M4a,
+    Wav,
+    /// the audio wasn't recognized, or the dump failed
+    Unknown,
+}
+
+impl OutputFormat {
+    /// the format with this file extension, as returned by `DumpOutput::extension`
+    fn from_extension(extension: &str) -> Self {
+        match extension {
+            "mp3" => Self::Mp3,
+            "flac" => Self::Flac,
+            "ogg" => Self::Ogg,
+            "m4a" => Self::M4a,
+            "wav" => Self::Wav,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl From<&AudioFileType> for OutputFormat {
+    fn from(format: &AudioFileType) -> Self {
+        match format {
+            AudioFileType::Mp3 => Self::Mp3,
+            AudioFileType::Flac => Self::Flac,
+            AudioFileType::Ogg => Self::Ogg,
+            AudioFileType::M4a => Self::M4a,
+            AudioFileType::Wav => Self::Wav,
+            AudioFileType::Unknown => Self::Unknown,
+        }
+    }
 }
 
-enum ImageFileType {
+#[derive(Clone, Copy)]
+pub(crate) enum ImageFileType {
     Jpeg,
     Png,
     Gif,
+    Webp,
+    Unknown,
 }
 
 impl AudioFileType {
-    fn from_header_data(header_data: &[u8]) -> Self {
-        match header_data[0..4] {
-            [0x66, 0x4c, 0x61, 0x43] => {
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Self::Flac => "flac",
+            Self::Mp3 => "mp3",
+            Self::Ogg => "ogg",
+            Self::M4a => "m4a",
+            Self::Wav => "wav",
+            Self::Unknown => "bin",
+        }
+    }
+
+    pub(crate) fn mime_type(&self) -> &'static str {
+        match self {
+            Self::Flac => "audio/flac",
+            Self::Mp3 => "audio/mpeg",
+            Self::Ogg => "audio/ogg",
+            Self::M4a => "audio/mp4",
+            Self::Wav => "audio/wav",
+            Self::Unknown => "application/octet-stream",
+        }
+    }
+
+    /// name of the detected format, which unlike the extension doesn't change if the
+    /// output is ever transcoded
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Unknown => "unknown",
+            _ => self.extension(),
+        }
+    }
+
+    pub(crate) fn from_header_data(header_data: &[u8]) -> Self {
+        match header_data {
+            [0x66, 0x4c, 0x61, 0x43, ..] => {
                 Self::Flac
             },
-            [0x49, 0x44, 0x33, _] => {
+            [0x49, 0x44, 0x33, ..] => {
+                Self::Mp3
+            },
+            // mpeg frame sync, for streams without an ID3 tag
+            [0xFF, b, ..] if b & 0xE0 == 0xE0 => {
                 Self::Mp3
             },
-            _ => Self::Mp3
+            [0x4F, 0x67, 0x67, 0x53, ..] => {
+                Self::Ogg
+            },
+            // mp4 container, starts with the size of the `ftyp` box
+            [_, _, _, _, b'f', b't', b'y', b'p', ..] => {
+                Self::M4a
+            },
+            // RIFF is shared with WEBP and AVI, the form type tells them apart
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => {
+                Self::Wav
+            },
+            _ => Self::Unknown
         }
     }
 }
 
 impl ImageFileType {
     fn from_header_data(header_data: &[u8]) -> Self {
-        match header_data[0..8] {
-            [137, 80, 78, 71, 13, 10, 26, 10] => Self::Png,
-            [0xFF, 0xD8, 0xFF, 0xE0, _, _, _, _] => Self::Jpeg,
-            [71, 73, 70, _, _, _, _, _] => Self::Gif,
-            _ => panic!("unknown image mime type")
+        match header_data {
+            [137, 80, 78, 71, 13, 10, 26, 10, ..] => Self::Png,
+            // JFIF, Exif and raw JPEGs differ in the marker after FF D8 FF
+            [0xFF, 0xD8, 0xFF, ..] => Self::Jpeg,
+            [71, 73, 70, ..] => Self::Gif,
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Self::Webp,
+            _ => Self::Unknown,
         }
     }
 }
 
-impl ToString for ImageFileType {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for ImageFileType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Png => "image/png".to_string(),
-            Self::Jpeg => "image/jpeg".to_string(),
-            Self::Gif => "image/gif".to_string(),
+            Self::Png => write!(f, "image/png"),
+            Self::Jpeg => write!(f, "image/jpeg"),
+            Self::Gif => write!(f, "image/gif"),
+            Self::Webp => write!(f, "image/webp"),
+            Self::Unknown => write!(f, "application/octet-stream"),
         }
     }
 }
 
-fn add_flac_metadata(audio: &mut Audio, image: &Option<Image>, metadata: &Option<Metadata>) -> DumpResult<()> {
-    if image.is_none() && metadata.is_none() {
-        return Ok(());
-    }
-    let audio_data = &audio.data;
-    let mut new_audio_data = Vec::new();
-    let mut cursor = Cursor::new(audio_data);
-    let mut tag = metaflac::Tag::read_from(&mut cursor).map_err(err_to_string)?;
-    let raw_data = metaflac::Tag::skip_metadata(&mut cursor);
-    let comment = tag.vorbis_comments_mut();
-    if let Some(metadata) = metadata {
-        comment.set_title(vec![metadata.music_name.clone()]);
-        comment.set_album(vec![metadata.album.clone()]);
-        comment.set_artist(metadata.artist.iter().map(|a| a.0.clone()).collect::<Vec<_>>());
+/// where the `fLaC` marker is, either at the start or right after an ID3v2 tag some encoders
+/// put in front of FLAC; `None` if it is in neither place
+pub(crate) fn flac_stream_offset(data: &[u8]) -> Option<usize> {
+    let offset = id3v2_tag_len(data);
+    data.get(offset..)?.starts_with(b"fLaC").then_some(offset)
+}
 
+/// format of the decrypted audio starting with `data`. An ID3v2 tag in front of FLAC looks like
+/// mp3 until the bytes after it are seen, `None` while `data` ends before them
+pub(crate) fn detect_audio_format(data: &[u8]) -> Option<AudioFileType> {
+    let tag_len = id3v2_tag_len(data);
+    if tag_len > 0 && data.len() < tag_len + 4 {
+        return None;
     }
-    if let Some(image) = image {
-        tag.add_picture(
-            image.format.to_string(),
-            metaflac::block::PictureType::CoverFront,
-            image.data.clone(),
-        );
+    match flac_stream_offset(data) {
+        Some(_) => Some(AudioFileType::Flac),
+        None => Some(AudioFileType::from_header_data(data)),
     }
-    tag.write_to(&mut new_audio_data).map_err(err_to_string)?;
-    new_audio_data.write_all(&raw_data).map_err(err_to_string)?;
-    audio.data = new_audio_data;
-    Ok(())
 }
 
-fn add_mp3_metadata(audio: &mut Audio, image: &Option<Image>, metadata: &Option<Metadata>) -> DumpResult<()> {
-    if image.is_none() && metadata.is_none() {
-        return Ok(())
-    }
-    let audio_data = &mut audio.data;
-    let mut new_audio_data = audio_data.clone();
-    let cursor = Cursor::new(audio_data);
-    let mut tag = id3::Tag::read_from(cursor).map_err(err_to_string)?;
-    if let Some(metadata) = metadata {
-        tag.set_title(metadata.music_name.clone());
-        tag.set_album(metadata.album.to_string());
-        tag.set_artist(metadata.artist.iter().map(|a| a.0.clone()).collect::<Vec<_>>().join("/"));
-    }
-    if let Some(image) = image {
-        tag.add_picture(
-            id3::frame::Picture {
-                mime_type: image.format.to_string(),
-                picture_type: id3::frame::PictureType::CoverFront,
-                data: image.data.clone(),
-                description: Default::default(),
-            }
-        );
+/// length of the ID3v2 tag at the start of `data` including its footer, 0 if there is none.
+/// Read from the header so it works without the `tagging` feature
+pub(crate) fn id3v2_tag_len(data: &[u8]) -> usize {
+    match data {
+        [b'I', b'D', b'3', _, _, flags, size @ ..] if size.len() >= 4 && size[..4].iter().all(|b| b & 0x80 == 0) => {
+            // the size is syncsafe, 7 bits per byte
+            let size = size[..4].iter().fold(0, |len, &b| len << 7 | b as usize);
+            let footer_len = if flags & 0x10 != 0 { 10 } else { 0 };
+            10 + size + footer_len
+        },
+        _ => 0,
     }
-    tag.write_to(&mut new_audio_data, id3::Version::Id3v24).map_err(err_to_string)?;
-    audio.data = new_audio_data;
-    Ok(())
 }
 
 #[wasm_bindgen]
 pub struct DumpOutput {
     data: Vec<u8>,
-    metadata: String,
+    metadata: Option<Metadata>,
     extension: String,
+    format: OutputFormat,
     result: String,
+    error_code: String,
+    cover: Vec<u8>,
+    cover_mime: String,
+    tagged: bool,
+    has_metadata: bool,
+    has_cover: bool,
+    format_mismatch: bool,
+    audio_size: u64,
+    input_size: u64,
+    source_hash: String,
+    timings: Option<Timings>,
+    format_version: u16,
+    audio_hash: String,
+    source_format: String,
+    mime_type: String,
+    warnings: Vec<String>,
 }
 
 #[wasm_bindgen]
+// the generated binding for the deprecated `new` calls it
+#[allow(deprecated)]
 impl DumpOutput {
-    pub fn new(data: Vec<u8>, metadata: String, result: String, extension: String) -> Self {
+    #[deprecated(note = "the arguments are easy to swap, use `success` or `error`")]
+    pub fn new(data: Vec<u8>, metadata: Option<Metadata>, result: String, extension: String) -> Self {
         Self {
             data,
             metadata,
+            format: OutputFormat::from_extension(&extension),
             extension,
-            result,
+            ..Self::with_result(result)
+        }
+    }
+
+    /// a successful output with the decoded audio, its file extension and the metadata it was
+    /// tagged with
+    pub fn success(data: Vec<u8>, extension: String, metadata: Option<Metadata>) -> Self {
+        Self {
+            data,
+            metadata,
+            format: OutputFormat::from_extension(&extension),
+            extension,
+            ..Self::with_result("ok".to_string())
         }
     }
 
+    /// a failed output, `message` becomes its `result`
+    pub fn error(message: String) -> Self {
+        Self::with_result(message)
+    }
+
+    /// consumes the output, use `take_data` to keep reading the other fields afterwards
     pub fn data(self) -> Vec<u8> {
         self.data
     }
 
-    pub fn metadata(&self) -> String {
+    /// move the data out without copying it, later calls return an empty buffer
+    pub fn take_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+
+    /// `data:` URL of the output for an `<audio src>`, empty on error. Base64 makes it a third
+    /// larger and it is one big string, so use it for short previews and `data`/`into_stream`
+    /// with a `Blob` URL for full tracks
+    pub fn data_url(&self) -> String {
+        if self.data.is_empty() {
+            return "".to_string();
+        }
+        format!("data:{};base64,{}", self.mime_type, base64::encode(&self.data))
+    }
+
+    /// hand out the data in chunks instead of copying it to JS all at once
+    pub fn into_stream(self) -> DumpStream {
+        DumpStream::new(self.data)
+    }
+
+    pub fn metadata(&self) -> Option<Metadata> {
         self.metadata.clone()
     }
 
+    pub fn metadata_json(&self) -> String {
+        serde_json::to_string(&self.metadata).unwrap_or_default()
+    }
+
+    pub fn suggested_filename(&self, options: Option<FilenameOptions>) -> String {
+        build_filename(self.metadata.as_ref(), &self.extension, &options.unwrap_or_default())
+    }
+
     pub fn extension(&self) -> String {
         self.extension.to_string()
     }
 
+    /// `extension` as an enum, `Unknown` on error
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    pub fn result(&self) -> String {
+        self.result.clone()
+    }
+
+    pub fn error_code(&self) -> String {
+        self.error_code.clone()
+    }
+
+    pub fn cover_bytes(&self) -> Vec<u8> {
+        self.cover.clone()
+    }
+
+    /// `[width, height]` of the cover read from its header, `[0, 0]` when there is no cover
+    /// or its header can't be parsed
+    pub fn cover_dimensions(&self) -> Vec<u32> {
+        let (width, height) = cover::dimensions(&self.cover).unwrap_or((0, 0));
+        vec![width, height]
+    }
+
+    pub fn cover_mime(&self) -> String {
+        self.cover_mime.clone()
+    }
+
+    /// every picture of the file as plain `{ mime, picture_type, bytes }` objects, where
+    /// `picture_type` is the FLAC/ID3 picture type (3 is the front cover). An ncm file carries
+    /// at most one picture, so this is empty or holds the front cover
+    pub fn covers(&self) -> js_sys::Array {
+        if self.cover.is_empty() {
+            return js_sys::Array::new();
+        }
+        let cover = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&cover, &"mime".into(), &self.cover_mime.as_str().into());
+        let _ = js_sys::Reflect::set(&cover, &"picture_type".into(), &(COVER_FRONT_PICTURE_TYPE as f64).into());
+        let _ = js_sys::Reflect::set(&cover, &"bytes".into(), &js_sys::Uint8Array::from(self.cover.as_slice()));
+        std::iter::once(cover).collect()
+    }
+
+    /// artists as plain `{ name, id }` objects, in the original order
+    pub fn artists(&self) -> js_sys::Array {
+        let artists = self.metadata.as_ref().map(|metadata| metadata.artist.as_slice()).unwrap_or_default();
+        artists.iter()
+            .map(|(name, id)| {
+                let artist = js_sys::Object::new();
+                // ids stay well below 2^53, a plain number is easier to use than a BigInt
+                let _ = js_sys::Reflect::set(&artist, &"name".into(), &name.into());
+                let _ = js_sys::Reflect::set(&artist, &"id".into(), &(*id as f64).into());
+                artist
+            })
+            .collect()
+    }
+
+    pub fn tagged(&self) -> bool {
+        self.tagged
+    }
+
+    /// whether the metadata was embedded into `data`, unlike `metadata()` which is also set
+    /// when the audio couldn't be tagged
+    pub fn has_metadata(&self) -> bool {
+        self.has_metadata
+    }
+
+    /// whether a cover was embedded into `data`; false when the file has none, the options
+    /// left it out or the audio couldn't be tagged
+    pub fn has_cover(&self) -> bool {
+        self.has_cover
+    }
+
+    /// whether the format named in the metadata disagrees with `source_format`, which hints
+    /// at a corrupt file
+    pub fn format_mismatch(&self) -> bool {
+        self.format_mismatch
+    }
+
+    /// `{ lossless, high_bitrate, raw, bits }`: whether the source is lossless and at least
+    /// 320 kbps, then the `flag` bitfield of the metadata with `bits` listing the indices of its
+    /// set bits. All false and 0 without metadata, see `QualityFlags`
+    pub fn quality_flags(&self) -> js_sys::Object {
+        let quality = self.metadata.as_ref().map(QualityFlags::from).unwrap_or_default();
+        let bits = (0..u64::BITS)
+            .filter(|bit| quality.raw & (1 << bit) != 0)
+            .map(JsValue::from)
+            .collect::<js_sys::Array>();
+        let flags = js_sys::Object::new();
+        let _ = js_sys::Reflect::set(&flags, &"lossless".into(), &quality.lossless.into());
+        let _ = js_sys::Reflect::set(&flags, &"high_bitrate".into(), &quality.high_bitrate.into());
+        // the flags seen in practice are small, a plain number is exact below 2^53
+        let _ = js_sys::Reflect::set(&flags, &"raw".into(), &(quality.raw as f64).into());
+        let _ = js_sys::Reflect::set(&flags, &"bits".into(), &bits);
+        flags
+    }
+
+    /// URL of the full size cover on NetEase's CDN, the embedded one is often a small thumbnail;
+    /// empty without metadata
+    pub fn album_pic_url(&self) -> String {
+        self.metadata.as_ref().map(|metadata| metadata.album_pic.clone()).unwrap_or_default()
+    }
+
+    /// lyrics found in the metadata, which are also embedded unless `set_lyrics` passed
+    /// others; `undefined` when the file has none
+    pub fn lyrics(&self) -> Option<String> {
+        self.metadata.as_ref().and_then(Metadata::lyrics)
+    }
+
+    pub fn bitrate(&self) -> u64 {
+        self.metadata.as_ref().map(|metadata| metadata.bitrate).unwrap_or(0)
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        self.metadata.as_ref().map(|metadata| metadata.duration).unwrap_or(0)
+    }
+
+    /// bytes of decrypted audio before tagging, 0 on error
+    pub fn audio_size(&self) -> u64 {
+        self.audio_size
+    }
+
+    /// bytes of the ncm file that was decoded, 0 on error
+    pub fn input_size(&self) -> u64 {
+        self.input_size
+    }
+
+    /// the two bytes after the magic header as a little-endian number, also set when the
+    /// version was rejected; 0 when the header couldn't be read
+    pub fn format_version(&self) -> u16 {
+        self.format_version
+    }
+
+    /// hex SHA-256 of the decrypted audio before tagging, for spotting duplicate tracks;
+    /// empty on error and unless `DumpOptions::set_compute_hashes` was set
+    pub fn audio_hash(&self) -> String {
+        self.audio_hash.clone()
+    }
+
+    /// milliseconds per step as `{ header, metadata, image, audio, tagging }`, `undefined`
+    /// unless `DumpOptions::set_collect_timings` was set
+    pub fn timings(&self) -> Option<js_sys::Object> {
+        let timings = self.timings?;
+        let object = js_sys::Object::new();
+        for (name, ms) in [
+            ("header", timings.header),
+            ("metadata", timings.metadata),
+            ("image", timings.image),
+            ("audio", timings.audio),
+            ("tagging", timings.tagging),
+        ].iter() {
+            let _ = js_sys::Reflect::set(&object, &(*name).into(), &(*ms).into());
+        }
+        Some(object)
+    }
+
+    /// hex SHA-256 of the encrypted ncm file, to skip files that were already decoded;
+    /// empty on error and unless `DumpOptions::set_compute_hashes` was set
+    pub fn source_hash(&self) -> String {
+        self.source_hash.clone()
+    }
+
+    /// format detected from the decrypted audio, e.g. `"flac"` or `"mp3"`, independent of
+    /// `extension`; empty on error
+    pub fn source_format(&self) -> String {
+        self.source_format.clone()
+    }
+
+    /// MIME type of `data` for building a `Blob`, e.g. `"audio/flac"`; empty on error
+    pub fn mime_type(&self) -> String {
+        self.mime_type.clone()
+    }
+
+    /// human readable problems that didn't stop the dump, like a missing cover
+    pub fn warnings(&self) -> js_sys::Array {
+        self.warnings.iter().map(|warning| JsValue::from_str(warning)).collect()
+    }
+}
+
+impl DumpOutput {
+    /// an output without data that only carries `result`
+    fn with_result(result: String) -> Self {
+        Self {
+            data: vec![],
+            metadata: None,
+            extension: "".to_string(),
+            format: OutputFormat::Unknown,
+            result,
+            error_code: "".to_string(),
+            cover: vec![],
+            cover_mime: "".to_string(),
+            tagged: false,
+            has_metadata: false,
+            has_cover: false,
+            format_mismatch: false,
+            audio_size: 0,
+            input_size: 0,
+            source_hash: "".to_string(),
+            timings: None,
+            format_version: 0,
+            audio_hash: "".to_string(),
+            source_format: "".to_string(),
+            mime_type: "".to_string(),
+            warnings: vec![],
+        }
+    }
+
+    pub(crate) fn from_error(err: DumpError) -> Self {
+        let format_version = match err {
+            DumpError::UnsupportedVersion(version) => version,
+            _ => 0,
+        };
+        Self {
+            error_code: err.code().to_string(),
+            format_version,
+            ..Self::error(err.to_string())
+        }
+    }
+}
+
+impl From<DecodedNcm> for DumpOutput {
+    fn from(decoded: DecodedNcm) -> Self {
+        let result = match &decoded.tag_error {
+            Some(err) => format!("partial: {}", err),
+            None => "ok".to_string(),
+        };
+        let (cover, cover_mime) = match decoded.image {
+            Some(cover) => (cover.bytes, cover.mime),
+            None => (vec![], "".to_string()),
+        };
+        DumpOutput {
+            data: decoded.audio,
+            metadata: decoded.metadata,
+            extension: decoded.extension,
+            format: decoded.format,
+            result,
+            error_code: "".to_string(),
+            cover,
+            cover_mime,
+            tagged: decoded.tagged,
+            has_metadata: decoded.has_metadata,
+            has_cover: decoded.has_cover,
+            format_mismatch: decoded.format_mismatch,
+            audio_size: decoded.audio_size,
+            input_size: decoded.input_size,
+            source_hash: decoded.source_hash,
+            timings: decoded.timings,
+            format_version: decoded.format_version,
+            audio_hash: decoded.audio_hash,
+            source_format: decoded.source_format,
+            mime_type: decoded.mime_type,
+            warnings: decoded.warnings,
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub struct MetadataOutput {
+    metadata: Option<Metadata>,
+    image: Vec<u8>,
+    result: String,
+    error_code: String,
+}
+
+#[wasm_bindgen]
+impl MetadataOutput {
+    pub fn new(metadata: Option<Metadata>, image: Vec<u8>, result: String) -> Self {
+        Self {
+            metadata,
+            image,
+            result,
+            error_code: "".to_string(),
+        }
+    }
+
+    pub fn metadata(&self) -> Option<Metadata> {
+        self.metadata.clone()
+    }
+
+    pub fn image(self) -> Vec<u8> {
+        self.image
+    }
+
     pub fn result(&self) -> String {
         self.result.clone()
     }
+
+    pub fn error_code(&self) -> String {
+        self.error_code.clone()
+    }
+}
+
+impl MetadataOutput {
+    fn from_error(err: DumpError) -> Self {
+        Self {
+            error_code: err.code().to_string(),
+            ..Self::new(None, vec![], err.to_string())
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub struct Cover {
+    bytes: Vec<u8>,
+    mime: String,
+}
+
+#[wasm_bindgen]
+impl Cover {
+    pub fn new(bytes: Vec<u8>, mime: String) -> Self {
+        Self {
+            bytes,
+            mime,
+        }
+    }
+
+    pub fn bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn mime(&self) -> String {
+        self.mime.clone()
+    }
 }
 
-fn err_to_string(err: impl std::error::Error) -> String {
-    err.to_string()
+impl From<Image> for Cover {
+    fn from(image: Image) -> Self {
+        Self::new(image.data, image.format.to_string())
+    }
 }
 
 trait ReaderExt {
@@ -390,3 +2066,145 @@ impl<T: AsRef<[u8]>> ReaderExt for Cursor<T> {
         Ok(u32::from_le_bytes(buf))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn key_box_matches_rc4_key_scheduling() {
+        let golden = unhex(concat!(
+            "4b33849dc0c81da84af383e4127082905b8fec2229b9cc5cbfd8ba0e6e4d0823",
+            "bc1b6789b6403b69d7f7ee7e8a1ae3371554684e8771ffac3859bb1c3e202d41",
+            "24fb9874bd076c2ecaa29f531f9a0be76a0d00d914e566765255b061d6970604",
+            "8ef5863ce1a5032756655a7fc54875922fc32a8064fdaed119ef72dbf4eaa3be",
+            "b7eb366299797b2628b4b38bcb4605182bc7e0d5d2dcadf11758c44ff23a0949",
+            "8da0c1b513e93f501e516fe2af96cfde1177e660475785c65fa99bd44231cd02",
+            "4c7325c23916dfb2100c5dedf021ce45359e940f7a88a1f6c92cab43b86dfc32",
+            "aa91958c5eda9cd001814430fea4faa7f87db1a6e8786b63f9dd347c0ad33d93",
+        ));
+        let key_box = build_key_box(b"Key").unwrap();
+        assert_eq!(key_box.to_vec(), golden);
+
+        // plain RC4 on the same box gives the published "Key" / "Plaintext" test vector
+        let mut state = key_box;
+        let (mut i, mut j) = (0u8, 0u8);
+        let ciphertext: Vec<u8> = b"Plaintext".iter().map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(state[i as usize]);
+            state.swap(i as usize, j as usize);
+            byte ^ state[state[i as usize].wrapping_add(state[j as usize]) as usize]
+        }).collect();
+        assert_eq!(ciphertext, unhex("bbf316e8d940af0ad3"));
+    }
+
+    #[test]
+    fn first_audio_bytes_match_a_reference_decode() {
+        let audio: Vec<u8> = [0xFF, 0xFB, 0x90, 0x64].iter().copied().chain(0..60).collect();
+        let ncm = crate::encode::encode_ncm(&audio, None, None).unwrap();
+        let key_box = NcmDecoder::new(&ncm[..]).read_header(false).unwrap().key_box;
+
+        // the ncm keystream written out byte by byte, without `decode_audio`
+        let audio_start = ncm.len() - audio.len();
+        let reference: Vec<u8> = ncm[audio_start..audio_start + 16].iter().enumerate().map(|(i, byte)| {
+            let j = (i + 1) & 0xff;
+            let k = (key_box[j] as usize + key_box[(key_box[j] as usize + j) & 0xff] as usize) & 0xff;
+            byte ^ key_box[k]
+        }).collect();
+
+        let decoded = decode_ncm(&ncm).unwrap();
+        assert_eq!(decoded.audio[..16], reference[..]);
+        assert_eq!(decoded.audio[..16], audio[..16]);
+    }
+
+    #[test]
+    fn peeks_flac_behind_an_id3_tag() {
+        let mut audio = b"ID3\x04\x00\x00\x00\x00\x00\x14".to_vec();
+        audio.extend_from_slice(&[0; 20]);
+        audio.extend_from_slice(b"fLaC\x80\x00\x00\x22");
+        let ncm = crate::encode::encode_ncm(&audio, None, None).unwrap();
+        assert!(matches!(NcmDecoder::new(&ncm[..]).peek_format().unwrap(), AudioFileType::Flac));
+        // cut inside the tag it can only be mp3
+        let cut = &ncm[..ncm.len() - audio.len() + 16];
+        assert!(matches!(NcmDecoder::new(cut).peek_format().unwrap(), AudioFileType::Mp3));
+    }
+
+    #[test]
+    fn peeked_extension_matches_the_dump() {
+        let mut mp3 = vec![0xFF, 0xFB, 0x90, 0x64];
+        mp3.resize(0x1A1, 0);
+        let mut flac = b"fLaC\x80\x00\x00\x22".to_vec();
+        flac.extend_from_slice(&[0x10, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0x0A, 0xC4, 0x42, 0xF0, 0, 0, 0, 0]);
+        flac.extend_from_slice(&[0; 16]);
+        let mut id3_flac = b"ID3\x04\x00\x00\x00\x00\x00\x14".to_vec();
+        id3_flac.extend_from_slice(&[0; 20]);
+        id3_flac.extend_from_slice(&flac);
+
+        for (audio, extension) in [(mp3, "mp3"), (flac, "flac"), (id3_flac, "flac")] {
+            let ncm = crate::encode::encode_ncm(&audio, None, None).unwrap();
+            let peeked = NcmDump::new_from_memory(ncm.clone()).peek_extension();
+            let dumped = NcmDump::new_from_memory(ncm).dump(None);
+            assert_eq!(dumped.result(), "ok");
+            assert_eq!(peeked, dumped.extension());
+            assert_eq!(peeked, extension);
+        }
+    }
+
+    #[test]
+    fn decodes_from_an_offset_without_scanning() {
+        let mut audio = vec![0xFF, 0xFB, 0x90, 0x64];
+        audio.resize(0x1A1, 0);
+        let mut padded = b"container".to_vec();
+        padded.extend(crate::encode::encode_ncm(&audio, None, None).unwrap());
+
+        let mut output = NcmDump::new_from_memory_at(padded.clone(), 9).dump(None);
+        assert_eq!(output.result(), "ok");
+        assert!(!output.warnings.iter().any(|warning| warning.starts_with("Skipping")), "{:?}", output.warnings);
+        assert_eq!(output.take_data(), audio);
+        // a scan from 0 would find the header, an explicit offset doesn't
+        assert_eq!(NcmDump::new_from_memory_at(padded.clone(), 8).dump(None).error_code, "not_ncm");
+        assert_eq!(NcmDump::new_from_memory_at(padded, 0).dump(None).error_code, "not_ncm");
+    }
+
+    #[test]
+    fn names_quality_and_keeps_the_raw_flag() {
+        let metadata = |json| serde_json::from_value::<Metadata>(json).unwrap();
+        let flac = metadata(serde_json::json!({ "format": "flac", "musicName": "", "artist": [], "album": "", "bitrate": 999000, "flag": 260 }));
+        assert_eq!(QualityFlags::from(&flac), QualityFlags { lossless: true, high_bitrate: true, raw: 260 });
+        let mp3 = metadata(serde_json::json!({ "format": "mp3", "musicName": "", "artist": [], "album": "", "bitrate": 128000 }));
+        assert_eq!(QualityFlags::from(&mp3), QualityFlags::default());
+    }
+
+    #[test]
+    fn key_box_rejects_empty_keys() {
+        assert!(build_key_box(&[]).is_err());
+    }
+
+    #[test]
+    fn two_step_dump_uses_the_options() {
+        let mut audio = vec![0xFF, 0xFB, 0x90, 0x64];
+        audio.resize(0x1A1, 0);
+        let ncm = crate::encode::encode_ncm(&audio, None, None).unwrap();
+        let mut options = DumpOptions::new();
+        options.set_collect_timings(true);
+        options.set_compute_hashes(true);
+
+        let mut dump = NcmDump::new_from_memory(ncm.clone());
+        assert_eq!(dump.parse_header().result, "ok");
+        let output = dump.decode_audio_now(&options, None);
+        assert_eq!(output.result, "ok");
+        assert!(output.timings.is_some());
+        assert!(!output.audio_hash.is_empty() && !output.source_hash.is_empty());
+
+        let mut unknown = ncm;
+        unknown[9] = 0x71;
+        options.set_strict(true);
+        let mut dump = NcmDump::new_from_memory(unknown);
+        assert_eq!(dump.parse_header().result, "ok");
+        assert_eq!(dump.decode_audio_now(&options, None).error_code, "unsupported_version");
+    }
+}
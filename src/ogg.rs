@@ -0,0 +1,210 @@
+use std::convert::TryInto;
+use metaflac::block::VorbisComment;
+use crate::error::{DumpError, DumpResult};
+
+const CAPTURE_PATTERN: [u8; 4] = *b"OggS";
+
+const VORBIS_COMMENT_HEADER: &[u8] = b"\x03vorbis";
+
+const OPUS_TAGS_HEADER: &[u8] = b"OpusTags";
+
+struct Page {
+    header_type: u8,
+    granule_position: u64,
+    serial: u32,
+    segments: Vec<u8>,
+    body: Vec<u8>,
+}
+
+/// Rewrite the comment header of an Ogg Vorbis/Opus stream.
+///
+/// The comment packet usually shares its pages with the setup header, so every header packet
+/// after the identification one is repaginated, and the sequence numbers and checksums of the
+/// audio pages that follow are updated to match.
+pub(crate) fn rewrite_comment(data: &[u8], update: impl FnOnce(&mut VorbisComment)) -> DumpResult<Vec<u8>> {
+    let pages = read_pages(data)?;
+    let first = pages.first().ok_or_else(|| malformed("no pages"))?;
+
+    let (comment_prefix, header_count) = if first.body.starts_with(b"\x01vorbis") {
+        (VORBIS_COMMENT_HEADER, 3)
+    } else if first.body.starts_with(b"OpusHead") {
+        (OPUS_TAGS_HEADER, 2)
+    } else {
+        return Err(malformed("unknown codec"));
+    };
+
+    // collect the header packets, remembering where the audio pages start
+    let mut packets: Vec<Vec<u8>> = vec![];
+    let mut current = vec![];
+    let mut audio_start = None;
+    'pages: for (page_index, page) in pages.iter().enumerate().skip(1) {
+        let mut offset = 0;
+        for &lacing in &page.segments {
+            current.extend_from_slice(&page.body[offset..offset + lacing as usize]);
+            offset += lacing as usize;
+            if lacing < 255 {
+                packets.push(std::mem::take(&mut current));
+                if packets.len() == header_count - 1 {
+                    if offset != page.body.len() {
+                        return Err(malformed("audio data shares a page with headers"));
+                    }
+                    audio_start = Some(page_index + 1);
+                    break 'pages;
+                }
+            }
+        }
+    }
+    let audio_start = audio_start.ok_or_else(|| malformed("incomplete headers"))?;
+
+    let comment_packet = &packets[0];
+    if !comment_packet.starts_with(comment_prefix) {
+        return Err(malformed("missing comment header"));
+    }
+    let mut comment = parse_comment(&comment_packet[comment_prefix.len()..])
+        .ok_or_else(|| malformed("invalid comment header"))?;
+    update(&mut comment);
+
+    let mut new_comment = comment_prefix.to_vec();
+    new_comment.extend(comment.to_bytes());
+    if comment_prefix == VORBIS_COMMENT_HEADER {
+        // framing bit
+        new_comment.push(1);
+    }
+    packets[0] = new_comment;
+
+    let mut output = Vec::with_capacity(data.len());
+    let mut sequence = 0;
+    write_page(&mut output, first, sequence);
+    sequence += 1;
+
+    for page in paginate(&packets, first.serial) {
+        write_page(&mut output, &page, sequence);
+        sequence += 1;
+    }
+    for page in &pages[audio_start..] {
+        write_page(&mut output, page, sequence);
+        sequence += 1;
+    }
+    Ok(output)
+}
+
+fn read_pages(data: &[u8]) -> DumpResult<Vec<Page>> {
+    let mut pages = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = data.get(offset..offset + 27).ok_or_else(|| malformed("truncated page header"))?;
+        if header[0..4] != CAPTURE_PATTERN {
+            return Err(malformed("missing capture pattern"));
+        }
+        let segment_count = header[26] as usize;
+        let segments = data.get(offset + 27..offset + 27 + segment_count)
+            .ok_or_else(|| malformed("truncated segment table"))?
+            .to_vec();
+        let body_start = offset + 27 + segment_count;
+        let body_len = segments.iter().map(|&s| s as usize).sum::<usize>();
+        let body = data.get(body_start..body_start + body_len)
+            .ok_or_else(|| malformed("truncated page"))?
+            .to_vec();
+        pages.push(Page {
+            header_type: header[5],
+            granule_position: u64::from_le_bytes([header[6], header[7], header[8], header[9], header[10], header[11], header[12], header[13]]),
+            serial: u32::from_le_bytes([header[14], header[15], header[16], header[17]]),
+            segments,
+            body,
+        });
+        offset = body_start + body_len;
+    }
+    Ok(pages)
+}
+
+/// lay out header packets on fresh pages, each packet starting on a new page
+fn paginate(packets: &[Vec<u8>], serial: u32) -> Vec<Page> {
+    let mut pages = vec![];
+    for packet in packets {
+        let mut lacing = vec![255u8; packet.len() / 255];
+        lacing.push((packet.len() % 255) as u8);
+
+        let mut offset = 0;
+        for (i, segments) in lacing.chunks(255).enumerate() {
+            let body_len = segments.iter().map(|&s| s as usize).sum::<usize>();
+            pages.push(Page {
+                header_type: if i == 0 { 0 } else { 1 },
+                granule_position: 0,
+                serial,
+                segments: segments.to_vec(),
+                body: packet[offset..offset + body_len].to_vec(),
+            });
+            offset += body_len;
+        }
+    }
+    pages
+}
+
+fn write_page(output: &mut Vec<u8>, page: &Page, sequence: u32) {
+    let start = output.len();
+    output.extend_from_slice(&CAPTURE_PATTERN);
+    output.push(0);
+    output.push(page.header_type);
+    output.extend_from_slice(&page.granule_position.to_le_bytes());
+    output.extend_from_slice(&page.serial.to_le_bytes());
+    output.extend_from_slice(&sequence.to_le_bytes());
+    output.extend_from_slice(&[0; 4]);
+    output.push(page.segments.len() as u8);
+    output.extend_from_slice(&page.segments);
+    output.extend_from_slice(&page.body);
+
+    let crc = crc32(&output[start..]);
+    output[start + 22..start + 26].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// same layout as a FLAC VORBIS_COMMENT block, but parsed without panicking on bad lengths
+fn parse_comment(bytes: &[u8]) -> Option<VorbisComment> {
+    fn read_string<'a>(bytes: &'a [u8], offset: &mut usize) -> Option<&'a [u8]> {
+        let len = u32::from_le_bytes(bytes.get(*offset..*offset + 4)?.try_into().ok()?) as usize;
+        let end = (*offset + 4).checked_add(len)?;
+        let value = bytes.get(*offset + 4..end)?;
+        *offset = end;
+        Some(value)
+    }
+
+    let mut comment = VorbisComment::new();
+    let mut offset = 0;
+    comment.vendor_string = String::from_utf8_lossy(read_string(bytes, &mut offset)?).into_owned();
+    let count = u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?);
+    offset += 4;
+    for _ in 0..count {
+        let entry = String::from_utf8_lossy(read_string(bytes, &mut offset)?).into_owned();
+        let mut parts = entry.splitn(2, '=');
+        let key = parts.next()?.to_ascii_uppercase();
+        let value = parts.next().unwrap_or_default().to_string();
+        comment.comments.entry(key).or_default().push(value);
+    }
+    Some(comment)
+}
+
+const CRC_TABLE: [u32; 256] = build_crc_table();
+
+const fn build_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut r = (i as u32) << 24;
+        let mut j = 0;
+        while j < 8 {
+            r = if r & 0x8000_0000 != 0 { (r << 1) ^ 0x04c1_1db7 } else { r << 1 };
+            j += 1;
+        }
+        table[i] = r;
+        i += 1;
+    }
+    table
+}
+
+/// ogg uses the unreflected CRC-32 with zero init and no final xor
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |crc, &b| (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize])
+}
+
+fn malformed(reason: &str) -> DumpError {
+    DumpError::TagError(format!("malformed ogg stream: {}", reason))
+}
@@ -0,0 +1,161 @@
+use wasm_bindgen::prelude::*;
+
+/// bytes of audio decrypted per step unless `DumpOptions::set_audio_chunk_size` says otherwise
+pub(crate) const DEFAULT_AUDIO_CHUNK_SIZE: usize = 0x8000;
+
+/// what to do with a GIF cover when embedding it into the audio
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GifCoverPolicy {
+    /// embed the GIF as is
+    Embed,
+    /// leave the cover out
+    Skip,
+    /// embed the first frame as a static PNG, the default
+    FirstFrame,
+}
+
+/// whether the cover is embedded when the audio already carries a picture
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverPolicy {
+    /// embed the cover whether or not the audio has a picture, the default
+    Always,
+    /// keep the existing picture and leave the cover out
+    IfMissing,
+}
+
+/// image format the embedded cover is converted to
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverFormat {
+    /// embed the cover in its own format, the default
+    Keep,
+    Jpeg,
+    Png,
+}
+
+#[wasm_bindgen]
+pub struct DumpOptions {
+    pub(crate) embed_metadata: bool,
+    pub(crate) embed_cover: bool,
+    pub(crate) parse_metadata: bool,
+    pub(crate) lyrics: Option<String>,
+    pub(crate) gif_cover_policy: GifCoverPolicy,
+    pub(crate) cover_policy: CoverPolicy,
+    pub(crate) cover_format: CoverFormat,
+    pub(crate) max_cover_bytes: Option<usize>,
+    pub(crate) collect_timings: bool,
+    pub(crate) compute_hashes: bool,
+    pub(crate) audio_chunk_size: usize,
+    pub(crate) strict: bool,
+    pub(crate) artist_separator: Option<String>,
+}
+
+#[wasm_bindgen]
+impl DumpOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            embed_metadata: true,
+            embed_cover: true,
+            parse_metadata: true,
+            lyrics: None,
+            gif_cover_policy: GifCoverPolicy::FirstFrame,
+            cover_policy: CoverPolicy::Always,
+            cover_format: CoverFormat::Keep,
+            max_cover_bytes: None,
+            collect_timings: false,
+            compute_hashes: false,
+            audio_chunk_size: DEFAULT_AUDIO_CHUNK_SIZE,
+            strict: false,
+            artist_separator: None,
+        }
+    }
+
+    /// write title/artist/album tags into the output audio; with this off and `embed_cover`
+    /// on only the picture is written
+    pub fn set_embed_metadata(&mut self, embed_metadata: bool) {
+        self.embed_metadata = embed_metadata;
+    }
+
+    /// write the cover image into the output audio
+    pub fn set_embed_cover(&mut self, embed_cover: bool) {
+        self.embed_cover = embed_cover;
+    }
+
+    /// decrypt and parse the metadata block at all; when disabled no metadata is returned or embedded
+    pub fn set_parse_metadata(&mut self, parse_metadata: bool) {
+        self.parse_metadata = parse_metadata;
+    }
+
+    /// lyrics to embed, usually the contents of a `.lrc` file; replaces lyrics found in the
+    /// metadata
+    pub fn set_lyrics(&mut self, lyrics: Option<String>) {
+        self.lyrics = lyrics;
+    }
+
+    /// how GIF covers are embedded, the cover returned in the output is never converted
+    pub fn set_gif_cover_policy(&mut self, policy: GifCoverPolicy) {
+        self.gif_cover_policy = policy;
+    }
+
+    /// whether the cover is embedded into audio that already has a picture; ncm audio
+    /// rarely does, this avoids a second cover when it does
+    pub fn set_cover_policy(&mut self, policy: CoverPolicy) {
+        self.cover_policy = policy;
+    }
+
+    /// convert the embedded cover, for players that only show one format. PNG and GIF
+    /// covers can be converted to JPEG and GIF ones to PNG; covers that can't be converted
+    /// are embedded as they are with a warning
+    pub fn set_cover_format(&mut self, cover_format: CoverFormat) {
+        self.cover_format = cover_format;
+    }
+
+    /// PNG covers larger than this are scaled down until they fit before they're embedded;
+    /// other covers, or ones that can't be shrunk, are embedded as they are with a warning.
+    /// `None`, the default, embeds every cover unchanged
+    pub fn set_max_cover_bytes(&mut self, max_cover_bytes: Option<usize>) {
+        self.max_cover_bytes = max_cover_bytes;
+    }
+
+    /// measure how long each step of the dump takes, see `DumpOutput::timings`; off by default
+    /// so there's no overhead unless asked for
+    pub fn set_collect_timings(&mut self, collect_timings: bool) {
+        self.collect_timings = collect_timings;
+    }
+
+    /// hash the ncm file and the decrypted audio, see `DumpOutput::source_hash` and
+    /// `DumpOutput::audio_hash`; off by default since each hash reads every byte once more
+    pub fn set_compute_hashes(&mut self, compute_hashes: bool) {
+        self.compute_hashes = compute_hashes;
+    }
+
+    /// bytes decrypted per step, which is also how often progress is reported; the output
+    /// doesn't depend on it. Defaults to 32 KiB
+    pub fn set_audio_chunk_size(&mut self, audio_chunk_size: usize) {
+        self.audio_chunk_size = audio_chunk_size;
+    }
+
+    /// fail with `missing_metadata` instead of warning when the file has no metadata block,
+    /// so every successful dump is fully tagged, with `format_mismatch` when the metadata
+    /// names another audio format than the one detected, and with `unsupported_version` when
+    /// the version bytes are neither `01 70` nor zeroed
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// joins the artists in the mp3 artist frame, e.g. `"; "`. By default ID3v2.4 tags use
+    /// real multi-value frames and ID3v2.3 tags `/`; an empty separator keeps that default.
+    /// FLAC and OGG store one comment per artist and ignore it
+    pub fn set_artist_separator(&mut self, artist_separator: Option<String>) {
+        self.artist_separator = artist_separator.filter(|separator| !separator.is_empty());
+    }
+}
+
+impl Default for DumpOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,346 @@
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use crate::error::{DumpError, DumpResult};
+use crate::options::{DumpOptions, DEFAULT_AUDIO_CHUNK_SIZE};
+use crate::ncm::{decode_audio, detect_audio_format, header_len, id3v2_tag_len, mismatched_format, record_warning, AudioFileType, AUDIO_HEADER_LEN, MAGIC_SEARCH_WINDOW, Cover, DecodedNcm, DumpOutput, Header, NcmDecoder, OutputFormat};
+
+/// Incremental decoder fed with arbitrary chunks of an ncm file.
+///
+/// The header is buffered until it can be parsed completely, after that every chunk is
+/// decrypted as it arrives. The output is the raw audio, it is not tagged.
+pub(crate) struct StreamDecoder {
+    buffer: Vec<u8>,
+    header: Option<Header>,
+    format: Option<AudioFileType>,
+    audio_offset: usize,
+    /// bytes pushed so far
+    input_size: u64,
+    warnings: Vec<String>,
+}
+
+impl StreamDecoder {
+    pub(crate) fn new() -> Self {
+        Self {
+            buffer: vec![],
+            header: None,
+            format: None,
+            audio_offset: 0,
+            input_size: 0,
+            warnings: vec![],
+        }
+    }
+
+    /// returns the audio that could be decoded so far, which may be empty
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> DumpResult<Vec<u8>> {
+        self.input_size += chunk.len() as u64;
+        if self.header.is_some() && self.format.is_some() {
+            let mut audio = chunk.to_vec();
+            self.decode(&mut audio);
+            return Ok(audio);
+        }
+        self.buffer.extend_from_slice(chunk);
+
+        if self.header.is_none() {
+            // the magic header check can't tell a short read from a wrong file, and the header
+            // may start anywhere in the search window
+            if self.buffer.len() < MAGIC_SEARCH_WINDOW + 8 {
+                return Ok(vec![]);
+            }
+            // decrypting the header again for every chunk would be quadratic, and would log
+            // its warnings each time
+            if matches!(header_len(&self.buffer), Some(len) if (self.buffer.len() as u64) < len) {
+                return Ok(vec![]);
+            }
+            let mut decoder = NcmDecoder::new(&self.buffer[..]);
+            match decoder.read_header(true) {
+                Ok(header) => {
+                    let header_len = decoder.position() as usize;
+                    self.warnings = decoder.take_warnings();
+                    self.buffer.drain(..header_len);
+                    self.header = Some(header);
+                },
+                Err(DumpError::TruncatedHeader) => return Ok(vec![]),
+                Err(err) => return Err(err),
+            }
+        }
+
+        match self.buffered_format() {
+            Some(format) => {
+                self.format = Some(format);
+                Ok(self.take_buffer())
+            },
+            None => Ok(vec![]),
+        }
+    }
+
+    /// flush whatever is still buffered, `audio` of the result only holds that remainder
+    pub(crate) fn finish(mut self) -> DumpResult<DecodedNcm> {
+        let header = self.header.take().ok_or(DumpError::TruncatedHeader)?;
+        let audio = if self.format.is_none() {
+            if self.buffer.is_empty() {
+                return Err(DumpError::NoAudioData);
+            }
+            self.take_buffer()
+        } else {
+            vec![]
+        };
+
+        let extension = self.format.as_ref().map(|format| format.extension()).unwrap_or_default();
+        let format = self.format.as_ref().map(OutputFormat::from).unwrap_or(OutputFormat::Unknown);
+        let source_format = self.format.as_ref().map(|format| format.name()).unwrap_or_default();
+        let mime_type = self.format.as_ref().map(|format| format.mime_type()).unwrap_or_default();
+        let declared_format = self.format.as_ref().and_then(|format| mismatched_format(header.metadata.as_ref(), format));
+        if let Some(declared) = declared_format {
+            record_warning(&mut self.warnings, format!("Metadata says the audio is {} but it was detected as {}", declared, source_format));
+        }
+        let format_mismatch = declared_format.is_some();
+        Ok(DecodedNcm {
+            audio,
+            // there are no options to ask for them
+            audio_hash: String::new(),
+            metadata: header.metadata,
+            image: header.image.map(Cover::from),
+            extension: extension.to_string(),
+            format,
+            source_format: source_format.to_string(),
+            mime_type: mime_type.to_string(),
+            audio_size: self.audio_offset as u64,
+            timings: None,
+            input_size: self.input_size,
+            source_hash: String::new(),
+            format_version: header.version.as_u16(),
+            tagged: false,
+            has_metadata: false,
+            has_cover: false,
+            format_mismatch,
+            tag_error: None,
+            warnings: self.warnings,
+        })
+    }
+
+    /// the audio format once enough is buffered to tell, an ID3v2 tag in front of the audio is
+    /// buffered whole so FLAC behind it is recognized
+    fn buffered_format(&self) -> Option<AudioFileType> {
+        let key_box = &self.header.as_ref()?.key_box;
+        let mut start = self.buffer.get(..AUDIO_HEADER_LEN)?.to_vec();
+        decode_audio(&mut start, 0, key_box);
+        let needed = (id3v2_tag_len(&start) + 4).max(AUDIO_HEADER_LEN);
+        let mut probe = self.buffer.get(..needed)?.to_vec();
+        decode_audio(&mut probe, 0, key_box);
+        detect_audio_format(&probe)
+    }
+
+    fn take_buffer(&mut self) -> Vec<u8> {
+        let mut audio = std::mem::take(&mut self.buffer);
+        self.decode(&mut audio);
+        if self.format.is_none() {
+            self.format = Some(detect_audio_format(&audio).unwrap_or_else(|| AudioFileType::from_header_data(&audio)));
+        }
+        audio
+    }
+
+    fn decode(&mut self, audio: &mut [u8]) {
+        if let Some(header) = &self.header {
+            decode_audio(audio, self.audio_offset, &header.key_box);
+            self.audio_offset += audio.len();
+        }
+    }
+}
+
+/// Finished output handed to JS in pieces, so saving a large file doesn't need one copy of
+/// the whole buffer on the JS heap.
+#[wasm_bindgen]
+pub struct DumpStream {
+    data: Vec<u8>,
+    offset: usize,
+}
+
+#[wasm_bindgen]
+impl DumpStream {
+    /// the next `size` bytes at most, `undefined` once everything was read
+    pub fn next_chunk(&mut self, size: usize) -> Option<js_sys::Uint8Array> {
+        if self.offset >= self.data.len() {
+            return None;
+        }
+        let end = self.offset.saturating_add(size.max(1)).min(self.data.len());
+        let chunk = js_sys::Uint8Array::from(&self.data[self.offset..end]);
+        self.offset = end;
+        Some(chunk)
+    }
+
+    /// total length of the output in bytes
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// bytes not yet returned by `next_chunk`
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.offset
+    }
+}
+
+impl DumpStream {
+    pub(crate) fn new(data: Vec<u8>) -> Self {
+        Self { data, offset: 0 }
+    }
+}
+
+/// Decode an ncm file from a `ReadableStream`, passing each decoded audio chunk to `on_chunk`
+/// as a `Uint8Array`. The returned output carries metadata, cover and extension but no data.
+pub(crate) async fn dump_stream(stream: web_sys::ReadableStream, on_chunk: js_sys::Function) -> DumpOutput {
+    match read_stream(stream, on_chunk).await {
+        Ok(decoded) => DumpOutput::from(decoded),
+        Err(err) => DumpOutput::from_error(err),
+    }
+}
+
+async fn read_stream(stream: web_sys::ReadableStream, on_chunk: js_sys::Function) -> DumpResult<DecodedNcm> {
+    let reader = stream.get_reader().unchecked_into::<web_sys::ReadableStreamDefaultReader>();
+    let mut decoder = StreamDecoder::new();
+
+    let emit = |audio: Vec<u8>| -> DumpResult<()> {
+        if audio.is_empty() {
+            return Ok(());
+        }
+        on_chunk.call1(&JsValue::NULL, &js_sys::Uint8Array::from(&audio[..]))
+            .map(|_| ())
+            .map_err(js_error)
+    };
+
+    let read: DumpResult<()> = async {
+        loop {
+            let result = JsFuture::from(reader.read()).await.map_err(js_error)?;
+            let done = js_sys::Reflect::get(&result, &JsValue::from_str("done")).map_err(js_error)?;
+            if done.is_truthy() {
+                return Ok(());
+            }
+            let value = js_sys::Reflect::get(&result, &JsValue::from_str("value")).map_err(js_error)?;
+            let chunk = js_sys::Uint8Array::new(&value).to_vec();
+            emit(decoder.push(&chunk)?)?;
+        }
+    }.await;
+    // hand the stream back to the caller whether or not it was read to the end
+    reader.release_lock();
+    read?;
+
+    let mut decoded = decoder.finish()?;
+    emit(std::mem::take(&mut decoded.audio))?;
+    Ok(decoded)
+}
+
+/// Decode `data` into `sink`, see the exported `dump_to_writable`. The returned output carries
+/// metadata, cover and extension but no data.
+pub(crate) async fn dump_to_writable(data: Vec<u8>, sink: web_sys::WritableStream, tag: bool) -> DumpOutput {
+    let writer = match sink.get_writer() {
+        Ok(writer) => writer,
+        Err(err) => return DumpOutput::from_error(js_error(err)),
+    };
+    let result = if tag {
+        write_tagged(data, &writer).await
+    } else {
+        write_raw(data, &writer).await
+    };
+    let result = match result {
+        Ok(decoded) => JsFuture::from(writer.close()).await.map(|_| decoded).map_err(js_error),
+        Err(err) => {
+            // don't leave a partial file behind looking complete
+            let _ = JsFuture::from(writer.abort()).await;
+            Err(err)
+        },
+    };
+    writer.release_lock();
+    match result {
+        Ok(decoded) => DumpOutput::from(decoded),
+        Err(err) => DumpOutput::from_error(err),
+    }
+}
+
+/// each chunk is written as soon as it is decrypted, the output is never held in full
+async fn write_raw(data: Vec<u8>, writer: &web_sys::WritableStreamDefaultWriter) -> DumpResult<DecodedNcm> {
+    let mut decoder = NcmDecoder::new(data);
+    let header = decoder.read_header(true)?;
+    let mut reader = decoder.start_audio(&header.key_box, DEFAULT_AUDIO_CHUNK_SIZE, u64::MAX)?;
+    loop {
+        let chunk = decoder.next_audio_chunk(&mut reader)?;
+        if chunk.is_empty() {
+            break;
+        }
+        write_chunk(writer, chunk).await?;
+    }
+    let audio = reader.finish();
+    decoder.finish_untagged(header, audio)
+}
+
+/// tags sit in front of the audio, FLAC's even have to be rewritten, so the tagged output is
+/// built in memory first and only written out in chunks
+async fn write_tagged(data: Vec<u8>, writer: &web_sys::WritableStreamDefaultWriter) -> DumpResult<DecodedNcm> {
+    let mut decoded = NcmDecoder::new(data)
+        .releasing_input()
+        .dump_async(&DumpOptions::default(), &mut |_, _| {})
+        .await?;
+    let audio = std::mem::take(&mut decoded.audio);
+    for chunk in audio.chunks(DEFAULT_AUDIO_CHUNK_SIZE) {
+        write_chunk(writer, chunk).await?;
+    }
+    Ok(decoded)
+}
+
+/// waiting for every write lets a slow sink hold back decoding
+async fn write_chunk(writer: &web_sys::WritableStreamDefaultWriter, chunk: &[u8]) -> DumpResult<()> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    JsFuture::from(writer.write_with_chunk(&js_sys::Uint8Array::from(chunk)))
+        .await
+        .map(|_| ())
+        .map_err(js_error)
+}
+
+fn js_error(err: JsValue) -> DumpError {
+    DumpError::Io(std::io::Error::other(format!("{:?}", err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_flac_behind_an_id3_tag_in_any_chunking() {
+        let mut audio = b"ID3\x04\x00\x00\x00\x00\x00\x14".to_vec();
+        audio.extend_from_slice(&[0; 20]);
+        audio.extend_from_slice(b"fLaC\x80\x00\x00\x22");
+        audio.extend_from_slice(&[0; 34]);
+        let ncm = crate::encode::encode_ncm(&audio, None, None).unwrap();
+        for chunk_size in [1, 7, ncm.len()] {
+            let mut decoder = StreamDecoder::new();
+            let mut output = vec![];
+            for chunk in ncm.chunks(chunk_size) {
+                output.extend(decoder.push(chunk).unwrap());
+            }
+            let decoded = decoder.finish().unwrap();
+            output.extend(decoded.audio);
+            assert_eq!(decoded.format, OutputFormat::Flac, "chunk size {}", chunk_size);
+            assert_eq!(output, audio);
+        }
+    }
+
+    #[test]
+    fn waits_for_the_whole_header_before_parsing_it() {
+        let metadata: crate::ncm::Metadata = serde_json::from_value(serde_json::json!({ "musicName": "", "artist": [], "album": "" })).unwrap();
+        let ncm = crate::encode::encode_ncm(&[0xFF, 0xFB, 0x90, 0x64], Some(&metadata), Some(b"\x89PNG\r\n\x1a\n")).unwrap();
+        let mut decoder = NcmDecoder::new(&ncm[..]);
+        decoder.read_header(true).unwrap();
+        let audio_offset = decoder.position();
+        assert_eq!(header_len(&ncm), Some(audio_offset));
+        assert_eq!(header_len(&ncm[..7]), None);
+        for len in 8..audio_offset as usize {
+            let needed = header_len(&ncm[..len]).unwrap();
+            assert!(needed > len as u64 && needed <= audio_offset, "{} bytes need {}", len, needed);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::io::{Cursor, Write};
+use crate::cover;
+use crate::error::{DumpError, DumpResult};
+use crate::ncm::{flac_stream_offset, record_warning, Audio, AudioFileType, Embedded, Image, ImageFileType, Metadata};
+use crate::ogg;
+use crate::options::{CoverFormat, CoverPolicy, DumpOptions, GifCoverPolicy};
+
+/// embed what `options` asks for into `audio`, returns what was written and the error if
+/// tagging failed
+pub(crate) fn tag_audio(audio: &mut Audio, metadata: Option<&Metadata>, image: Option<&Image>, options: &DumpOptions, warnings: &mut Vec<String>) -> (Embedded, Option<DumpError>) {
+    let embedded_image = image
+        .filter(|_| options.embed_cover)
+        .and_then(|image| embedded_cover(image, options.gif_cover_policy, warnings))
+        .map(|image| convert_cover(image, options.cover_format, warnings))
+        .map(|image| match options.max_cover_bytes {
+            Some(max_bytes) if image.data.len() > max_bytes => shrink_cover(image, max_bytes, warnings),
+            _ => image,
+        });
+    let metadata = metadata.filter(|_| options.embed_metadata);
+    // lyrics passed in the options win over the ones in the metadata
+    let metadata_lyrics = metadata.and_then(Metadata::lyrics);
+    let tags = Tags {
+        metadata,
+        image: embedded_image.as_deref(),
+        lyrics: options.lyrics.as_deref().or(metadata_lyrics.as_deref()),
+        artist_separator: options.artist_separator.as_deref(),
+        cover_policy: options.cover_policy,
+    };
+    let tag_result = match audio.format {
+        AudioFileType::Flac => add_flac_metadata(audio, &tags, warnings).map(Some),
+        AudioFileType::Mp3 => add_mp3_metadata(audio, &tags, warnings).map(Some),
+        AudioFileType::Ogg => add_ogg_metadata(audio, &tags).map(Some),
+        AudioFileType::M4a => {
+            record_warning(warnings, "Tagging m4a is not supported, skip tagging".to_string());
+            Ok(None)
+        },
+        AudioFileType::Wav => {
+            record_warning(warnings, "Tagging wav is not supported, skip tagging".to_string());
+            Ok(None)
+        },
+        AudioFileType::Unknown => {
+            record_warning(warnings, "Unknown audio format, skip tagging".to_string());
+            Ok(None)
+        },
+    };
+    // the taggers leave the audio untouched on failure, so it is still usable
+    match tag_result {
+        Ok(Some(cover)) => (Embedded {
+            tagged: !tags.is_empty(),
+            metadata: tags.metadata.is_some(),
+            cover,
+        }, None),
+        Ok(None) => (Embedded::default(), None),
+        Err(err) => {
+            record_warning(warnings, format!("Failed to write tags, keeping untagged audio: {}", err));
+            (Embedded::default(), Some(err))
+        },
+    }
+}
+
+/// the cover to embed once the gif policy is applied, `None` when it should be left out.
+/// Animated GIFs are shown as a broken or blank image by many players.
+fn embedded_cover<'a>(image: &'a Image, policy: GifCoverPolicy, warnings: &mut Vec<String>) -> Option<Cow<'a, Image>> {
+    if !matches!(image.format, ImageFileType::Gif) {
+        return Some(Cow::Borrowed(image));
+    }
+    match policy {
+        GifCoverPolicy::Embed => Some(Cow::Borrowed(image)),
+        GifCoverPolicy::Skip => {
+            record_warning(warnings, "Cover is a GIF, skip embedding it".to_string());
+            None
+        },
+        GifCoverPolicy::FirstFrame => match cover::gif_first_frame(&image.data) {
+            Ok(data) => {
+                record_warning(warnings, "Cover is a GIF, embedding its first frame as a PNG".to_string());
+                Some(Cow::Owned(Image {
+                    format: ImageFileType::Png,
+                    data,
+                }))
+            },
+            Err(err) => {
+                record_warning(warnings, format!("Failed to convert GIF cover, skip embedding it: {}", err));
+                None
+            },
+        },
+    }
+}
+
+/// `image` converted to `format`, or as it is with a warning if that fails
+fn convert_cover<'a>(image: Cow<'a, Image>, format: CoverFormat, warnings: &mut Vec<String>) -> Cow<'a, Image> {
+    let converted = match (format, &image.format) {
+        (CoverFormat::Keep, _) | (CoverFormat::Jpeg, ImageFileType::Jpeg) | (CoverFormat::Png, ImageFileType::Png) => return image,
+        (CoverFormat::Jpeg, ImageFileType::Png) | (CoverFormat::Jpeg, ImageFileType::Gif) => cover::to_jpeg(&image.data)
+            .map(|data| Image { format: ImageFileType::Jpeg, data }),
+        (CoverFormat::Png, ImageFileType::Gif) => cover::gif_first_frame(&image.data)
+            .map(|data| Image { format: ImageFileType::Png, data }),
+        (_, format) => Err(DumpError::InvalidCover(format!("can't decode {}", format))),
+    };
+    match converted {
+        Ok(converted) => Cow::Owned(converted),
+        Err(err) => {
+            record_warning(warnings, format!("Failed to convert the cover to {:?}, embedding it as is: {}", format, err));
+            image
+        },
+    }
+}
+
+/// `image` scaled down to at most `max_bytes`, or as it is with a warning if that fails.
+/// Only PNG can be re-encoded
+fn shrink_cover<'a>(image: Cow<'a, Image>, max_bytes: usize, warnings: &mut Vec<String>) -> Cow<'a, Image> {
+    let shrunk = match image.format {
+        ImageFileType::Png => cover::shrink_png(&image.data, max_bytes),
+        format => Err(DumpError::InvalidCover(format!("can't re-encode {}", format))),
+    };
+    match shrunk {
+        Ok(data) => Cow::Owned(Image {
+            format: ImageFileType::Png,
+            data,
+        }),
+        Err(err) => {
+            record_warning(warnings, format!("Cover is larger than {} bytes, embedding it as is: {}", max_bytes, err));
+            image
+        },
+    }
+}
+
+/// everything that gets embedded into the output audio
+struct Tags<'a> {
+    metadata: Option<&'a Metadata>,
+    image: Option<&'a Image>,
+    lyrics: Option<&'a str>,
+    /// joins the artists of the single mp3 artist frame instead of the version's convention
+    artist_separator: Option<&'a str>,
+    cover_policy: CoverPolicy,
+}
+
+impl Tags<'_> {
+    fn is_empty(&self) -> bool {
+        self.metadata.is_none() && self.image.is_none() && self.lyrics.is_none()
+    }
+
+    /// the cover to write into audio that does or doesn't have a picture yet
+    fn image_for(&self, has_picture: bool) -> Option<&Image> {
+        self.image.filter(|_| !has_picture || self.cover_policy == CoverPolicy::Always)
+    }
+}
+
+/// the taggers return whether they wrote a cover
+fn add_flac_metadata(audio: &mut Audio, tags: &Tags, warnings: &mut Vec<String>) -> DumpResult<bool> {
+    if tags.is_empty() {
+        return Ok(false);
+    }
+    let flac_offset = flac_stream_offset(&audio.data).ok_or(DumpError::InvalidFlacStream)?;
+    // checked first so a failure leaves the audio untouched
+    check_flac_blocks(&audio.data[flac_offset..])?;
+    if flac_offset > 0 {
+        record_warning(warnings, format!("Stripping {} bytes before the `fLaC` marker", flac_offset));
+        audio.data.drain(..flac_offset);
+    }
+    let audio_data = &audio.data;
+    let mut new_audio_data = Vec::new();
+    let mut cursor = Cursor::new(audio_data);
+    let mut tag = metaflac::Tag::read_from(&mut cursor)?;
+    let raw_data = metaflac::Tag::skip_metadata(&mut cursor);
+    let has_picture = tag.pictures().next().is_some();
+    fill_vorbis_comment(tag.vorbis_comments_mut(), tags);
+    let image = tags.image_for(has_picture);
+    if let Some(image) = image {
+        tag.add_picture(
+            image.format.to_string(),
+            metaflac::block::PictureType::CoverFront,
+            image.data.clone(),
+        );
+    }
+    tag.write_to(&mut new_audio_data)?;
+    new_audio_data.write_all(&raw_data)?;
+    audio.data = new_audio_data;
+    Ok(image.is_some())
+}
+
+/// walk the metadata blocks after `fLaC` the way metaflac reads them; it panics instead of
+/// failing on blocks that are cut off or whose fields run past their end
+fn check_flac_blocks(data: &[u8]) -> DumpResult<()> {
+    let malformed = |problem: String| DumpError::TagError(format!("malformed FLAC metadata, {}", problem));
+    let mut offset = 4;
+    loop {
+        let header = data.get(offset..offset + 4).ok_or_else(|| malformed("a block header is cut off".to_string()))?;
+        let (is_last, block_type) = (header[0] & 0x80 != 0, header[0] & 0x7F);
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let block = data.get(offset + 4..offset + 4 + len)
+            .ok_or_else(|| malformed(format!("block type {} is cut off", block_type)))?;
+        let fits = match block_type {
+            0 => len == 34,
+            2 => len >= 4,
+            4 => vorbis_comment_fits(block).is_some(),
+            5 => cue_sheet_fits(block).is_some(),
+            6 => picture_fits(block).is_some(),
+            _ => true,
+        };
+        if !fits {
+            return Err(malformed(format!("block type {} doesn't fit its {} bytes", block_type, len)));
+        }
+        if is_last {
+            return Ok(());
+        }
+        offset += 4 + len;
+    }
+}
+
+/// the fields of a FLAC metadata block in order, `None` once one runs past the end
+struct BlockFields<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BlockFields<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.offset.checked_add(len)?;
+        let field = self.bytes.get(self.offset..end)?;
+        self.offset = end;
+        Some(field)
+    }
+
+    fn be_u32(&mut self) -> Option<usize> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?) as usize)
+    }
+
+    fn le_u32(&mut self) -> Option<usize> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?) as usize)
+    }
+}
+
+/// vendor and comments, each comment needs a `=`
+fn vorbis_comment_fits(bytes: &[u8]) -> Option<()> {
+    let mut fields = BlockFields { bytes, offset: 0 };
+    let vendor_len = fields.le_u32()?;
+    fields.take(vendor_len)?;
+    for _ in 0..fields.le_u32()? {
+        let comment_len = fields.le_u32()?;
+        fields.take(comment_len)?.contains(&b'=').then_some(())?;
+    }
+    Some(())
+}
+
+/// type, MIME type, description, dimensions and data
+fn picture_fits(bytes: &[u8]) -> Option<()> {
+    let mut fields = BlockFields { bytes, offset: 0 };
+    fields.take(4)?;
+    let mime_len = fields.be_u32()?;
+    fields.take(mime_len)?;
+    let description_len = fields.be_u32()?;
+    fields.take(description_len)?;
+    fields.take(16)?;
+    let data_len = fields.be_u32()?;
+    fields.take(data_len).map(|_| ())
+}
+
+/// catalog number, lead-in, flags and reserved bytes, then the tracks with their indices
+fn cue_sheet_fits(bytes: &[u8]) -> Option<()> {
+    let mut fields = BlockFields { bytes, offset: 0 };
+    fields.take(128 + 8 + 1 + 258)?;
+    for _ in 0..fields.take(1)?[0] {
+        fields.take(8 + 1 + 12 + 1 + 13)?;
+        let indices = fields.take(1)?[0] as usize;
+        fields.take(indices * 12)?;
+    }
+    Some(())
+}
+
+fn add_ogg_metadata(audio: &mut Audio, tags: &Tags) -> DumpResult<bool> {
+    if tags.is_empty() {
+        return Ok(false);
+    }
+    let mut has_cover = false;
+    audio.data = ogg::rewrite_comment(&audio.data, |comment| {
+        let has_picture = comment.get("METADATA_BLOCK_PICTURE").is_some();
+        fill_vorbis_comment(comment, tags);
+        if let Some(image) = tags.image_for(has_picture) {
+            has_cover = true;
+            let picture = metaflac::block::Picture {
+                picture_type: metaflac::block::PictureType::CoverFront,
+                mime_type: image.format.to_string(),
+                data: image.data.clone(),
+                ..metaflac::block::Picture::new()
+            };
+            comment.set("METADATA_BLOCK_PICTURE", vec![base64::encode(picture.to_bytes())]);
+        }
+    })?;
+    Ok(has_cover)
+}
+
+/// vorbis comments are shared by FLAC and OGG, pictures are stored differently so they're left out
+fn fill_vorbis_comment(comment: &mut metaflac::block::VorbisComment, tags: &Tags) {
+    if let Some(lyrics) = tags.lyrics {
+        comment.set("LYRICS", vec![lyrics]);
+    }
+    let metadata = match tags.metadata {
+        Some(metadata) => metadata,
+        None => return,
+    };
+    comment.set_title(vec![metadata.music_name.clone()]);
+    comment.set_album(vec![metadata.album.clone()]);
+    comment.set_artist(metadata.artist.iter().map(|a| a.0.clone()).collect::<Vec<_>>());
+    if let Some(album_artist) = metadata.album_artist() {
+        comment.set_album_artist(vec![album_artist]);
+    }
+    if let Some(track_number) = metadata.track_number {
+        comment.set_track(track_number);
+    }
+    if let Some(disc_number) = metadata.disc_number {
+        comment.set("DISCNUMBER", vec![disc_number.to_string()]);
+    }
+    if let Some(year) = metadata.release_year() {
+        comment.set("DATE", vec![year.to_string()]);
+    }
+    if let Some(genre) = &metadata.genre {
+        comment.set("GENRE", vec![genre.clone()]);
+    }
+    if let Some(description) = metadata.comment() {
+        comment.set("DESCRIPTION", vec![description]);
+    }
+    if !metadata.trans_names.is_empty() {
+        comment.set("TRANSLATEDTITLE", metadata.trans_names.clone());
+    }
+    for (key, id) in source_ids(metadata) {
+        comment.set(key, vec![id.to_string()]);
+    }
+}
+
+fn add_mp3_metadata(audio: &mut Audio, tags: &Tags, warnings: &mut Vec<String>) -> DumpResult<bool> {
+    if tags.is_empty() {
+        return Ok(false)
+    }
+    let mut cursor = Cursor::new(&audio.data);
+    // start from the existing tag so frames we don't write (encoder, replaygain...) survive
+    let mut tag = match id3::Tag::read_from(&mut cursor) {
+        Ok(tag) => tag,
+        Err(id3::Error { kind: id3::ErrorKind::NoTag, .. }) => id3::Tag::new(),
+        Err(id3::Error { partial_tag: Some(tag), description, .. }) => {
+            record_warning(warnings, format!("Existing ID3 tag is partially broken, keeping readable frames: {}", description));
+            tag
+        },
+        Err(err) => return Err(err.into()),
+    };
+    // keep the original version, v2.2 can't be written so it is upgraded
+    let version = match tag.version() {
+        id3::Version::Id3v23 => id3::Version::Id3v23,
+        _ => id3::Version::Id3v24,
+    };
+    // where the mpeg frames start, after the existing tag if there is one
+    cursor.set_position(0);
+    id3::Tag::skip(&mut cursor)?;
+    let frames_offset = cursor.position() as usize;
+
+    if let Some(metadata) = tags.metadata {
+        tag.set_title(metadata.music_name.clone());
+        tag.set_album(metadata.album.to_string());
+        // v2.4 has real multi-value text frames, v2.3 players expect the `/` convention
+        let artist_separator = match version {
+            id3::Version::Id3v24 => "\0",
+            _ => "/",
+        };
+        tag.set_artist(metadata.artist.iter().map(|a| a.0.clone()).collect::<Vec<_>>().join(tags.artist_separator.unwrap_or(artist_separator)));
+        if let Some(album_artist) = metadata.album_artist() {
+            tag.set_album_artist(album_artist);
+        }
+        if let Some(track_number) = metadata.track_number {
+            tag.set_track(track_number);
+        }
+        if let Some(disc_number) = metadata.disc_number {
+            tag.set_disc(disc_number);
+        }
+        if let Some(year) = metadata.release_year() {
+            // TYER only exists in v2.3, v2.4 replaced it with the TDRC timestamp
+            match version {
+                id3::Version::Id3v24 => {
+                    tag.remove_year();
+                    tag.set_date_recorded(id3::Timestamp { year, month: None, day: None, hour: None, minute: None, second: None });
+                },
+                _ => tag.set_year(year),
+            }
+        }
+        if let Some(genre) = &metadata.genre {
+            tag.set_genre(genre.clone());
+        }
+        if let Some(comment) = metadata.comment() {
+            tag.add_comment(id3::frame::Comment {
+                lang: "und".to_string(),
+                description: String::new(),
+                text: comment,
+            });
+        }
+        if !metadata.trans_names.is_empty() {
+            tag.add_extended_text("TRANSLATED_NAME", metadata.trans_names.join(artist_separator));
+        }
+        for (key, id) in source_ids(metadata) {
+            tag.add_extended_text(key, id.to_string());
+        }
+    }
+    if let Some(lyrics) = tags.lyrics {
+        tag.add_lyrics(id3::frame::Lyrics {
+            lang: "und".to_string(),
+            description: String::new(),
+            text: lyrics.to_string(),
+        });
+    }
+    let has_picture = tag.pictures().next().is_some();
+    let image = tags.image_for(has_picture);
+    if let Some(image) = image {
+        tag.add_picture(
+            id3::frame::Picture {
+                mime_type: image.format.to_string(),
+                picture_type: id3::frame::PictureType::CoverFront,
+                data: image.data.clone(),
+                description: Default::default(),
+            }
+        );
+    }
+    // only the tag is rebuilt, the frames are kept in the original buffer
+    let mut tag_data = Vec::new();
+    tag.write_to(&mut tag_data, version)?;
+    audio.data.splice(..frames_offset, tag_data);
+    Ok(image.is_some())
+}
+
+/// NetEase ids written as custom tags so files can be traced back to their source
+fn source_ids(metadata: &Metadata) -> Vec<(&'static str, u64)> {
+    vec![
+        ("NETEASE_ID", metadata.music_id),
+        ("NETEASE_ALBUM_ID", metadata.album_id),
+        ("NETEASE_MV_ID", metadata.mv_id),
+    ].into_iter().filter(|(_, id)| *id != 0).collect()
+}
@@ -0,0 +1,114 @@
+//! fixtures shared by the integration tests, ncm files are built with `encode_ncm`
+#![allow(dead_code)]
+
+use std::convert::TryInto;
+use aes::Aes128;
+use block_modes::block_padding::Pkcs7;
+use block_modes::{BlockMode, Ecb};
+use ncmdump_wasm::Metadata;
+use serde_json::{json, Value};
+
+/// the AES key NetEase encrypts the key block with
+pub const CORE_KEY: [u8; 16] = [0x68, 0x7A, 0x48, 0x52, 0x41, 0x6D, 0x73, 0x6F, 0x35, 0x6B, 0x49, 0x6E, 0x62, 0x61, 0x78, 0x57];
+
+/// the AES key NetEase encrypts the metadata block with
+pub const MODIFY_KEY: [u8; 16] = [0x23, 0x31, 0x34, 0x6C, 0x6A, 0x6B, 0x5F, 0x21, 0x5C, 0x5D, 0x26, 0x30, 0x55, 0x3C, 0x27, 0x28];
+
+/// mpeg frames without an ID3 tag, `n` frames of 0x1A1 bytes
+pub fn mp3(n: usize) -> Vec<u8> {
+    let mut frame = vec![0xFF, 0xFB, 0x90, 0x64];
+    frame.resize(0x1A1, 0x55);
+    frame.repeat(n)
+}
+
+/// a STREAMINFO only FLAC stream followed by one frame header
+pub fn flac() -> Vec<u8> {
+    let mut data = b"fLaC".to_vec();
+    data.extend_from_slice(&[0x80, 0, 0, 34]);
+    data.extend_from_slice(&[0x10, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0x0A, 0xC4, 0x42, 0xF0, 0, 0, 0, 0]);
+    data.extend_from_slice(&[0; 16]);
+    data.extend_from_slice(&[0xFF, 0xF8, 0, 0, 0, 0, 0, 0]);
+    data
+}
+
+/// `flac()` behind a 30 byte ID3v2 tag, the way some encoders write it
+pub fn id3_flac() -> Vec<u8> {
+    let mut data = b"ID3\x04\x00\x00\x00\x00\x00\x14".to_vec();
+    data.extend_from_slice(&[0; 20]);
+    data.extend_from_slice(&flac());
+    data
+}
+
+/// metadata as NetEase writes it, `extra` is merged over the defaults
+pub fn metadata_json(format: &str, extra: Value) -> Value {
+    let mut metadata = json!({
+        "format": format,
+        "musicId": 1,
+        "musicName": "Title",
+        "artist": [["Artist", 2]],
+        "album": "Album",
+        "albumId": 3,
+    });
+    if let (Some(metadata), Value::Object(extra)) = (metadata.as_object_mut(), extra) {
+        metadata.extend(extra);
+    }
+    metadata
+}
+
+pub fn metadata(format: &str, extra: Value) -> Metadata {
+    serde_json::from_value(metadata_json(format, extra)).unwrap()
+}
+
+/// an opaque `width` x `height` png
+#[cfg(feature = "tagging")]
+pub fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    let pixels: Vec<u8> = (0..width * height).flat_map(|i| [(i * 7) as u8, (i * 3) as u8, 0x80]).collect();
+    writer.write_image_data(&pixels).unwrap();
+    drop(writer);
+    data
+}
+
+/// a metadata block holding `plaintext` as is, for layouts `encode_ncm` doesn't write; `prefix`
+/// is normally `163 key(Don't modify):` and `plaintext` starts with `music:`
+pub fn metadata_block(prefix: &str, plaintext: &[u8]) -> Vec<u8> {
+    let cipher = Ecb::<Aes128, Pkcs7>::new_var(&MODIFY_KEY, Default::default()).unwrap();
+    let mut block = [prefix.as_bytes(), base64::encode(cipher.encrypt_vec(plaintext)).as_bytes()].concat();
+    block.iter_mut().for_each(|b| *b ^= 0x63);
+    block
+}
+
+/// `ncm` with its metadata block swapped for `block`
+pub fn replace_metadata(ncm: &[u8], block: &[u8]) -> Vec<u8> {
+    let le32 = |offset: usize| u32::from_le_bytes(ncm[offset..offset + 4].try_into().unwrap()) as usize;
+    let meta_start = 14 + le32(10);
+    let meta_end = meta_start + 4 + le32(meta_start);
+    let mut output = ncm[..meta_start].to_vec();
+    output.extend_from_slice(&(block.len() as u32).to_le_bytes());
+    output.extend_from_slice(block);
+    output.extend_from_slice(&ncm[meta_end..]);
+    output
+}
+
+/// a key block holding `plaintext` as is, which normally is `neteasecloudmusic` followed by
+/// the RC4 key
+pub fn key_block(plaintext: &[u8]) -> Vec<u8> {
+    let cipher = Ecb::<Aes128, Pkcs7>::new_var(&CORE_KEY, Default::default()).unwrap();
+    let mut block = cipher.encrypt_vec(plaintext);
+    block.iter_mut().for_each(|b| *b ^= 0x64);
+    block
+}
+
+/// `ncm` with its key block swapped for `block`
+pub fn replace_key(ncm: &[u8], block: &[u8]) -> Vec<u8> {
+    let key_end = 14 + u32::from_le_bytes(ncm[10..14].try_into().unwrap()) as usize;
+    let mut output = ncm[..10].to_vec();
+    output.extend_from_slice(&(block.len() as u32).to_le_bytes());
+    output.extend_from_slice(block);
+    output.extend_from_slice(&ncm[key_end..]);
+    output
+}
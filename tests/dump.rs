@@ -0,0 +1,96 @@
+mod common;
+
+use common::{key_block, metadata, mp3, replace_key};
+use ncmdump_wasm::{decode_ncm_with_options, encode_ncm, is_ncm, DumpError, DumpOptions};
+use serde_json::json;
+
+#[test]
+fn collects_timings_natively() {
+    let ncm = encode_ncm(&mp3(4), Some(&metadata("mp3", json!({}))), None).unwrap();
+    let mut options = DumpOptions::new();
+    options.set_collect_timings(true);
+    let decoded = decode_ncm_with_options(&ncm, &options).unwrap();
+    let timings = decoded.timings.unwrap();
+    assert!(timings.header >= 0.0 && timings.audio >= 0.0 && timings.tagging >= 0.0);
+}
+
+#[test]
+fn the_first_100_bytes_are_a_clean_error() {
+    let ncm = encode_ncm(&mp3(8), Some(&metadata("mp3", json!({}))), None).unwrap();
+    assert!(ncm.len() > 100);
+    assert!(matches!(decode_ncm_with_options(&ncm[..100], &DumpOptions::new()), Err(DumpError::TruncatedHeader)));
+}
+
+#[test]
+fn degenerate_key_blocks_are_an_error() {
+    let ncm = encode_ncm(&mp3(4), Some(&metadata("mp3", json!({}))), None).unwrap();
+    for block in [vec![], vec![0; 16], key_block(b""), key_block(b"neteasecloudmusic")] {
+        let result = decode_ncm_with_options(&replace_key(&ncm, &block), &DumpOptions::new());
+        assert!(result.is_err(), "key block {:02x?}", block);
+    }
+    // any other key decodes, if only to noise
+    let other_key = replace_key(&ncm, &key_block(b"neteasecloudmusic0123"));
+    assert!(decode_ncm_with_options(&other_key, &DumpOptions::new()).is_ok());
+}
+
+#[test]
+fn is_ncm_rejects_huge_key_lengths() {
+    let mut ncm = encode_ncm(&mp3(1), None, None).unwrap();
+    assert!(is_ncm(&ncm));
+    ncm[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(!is_ncm(&ncm));
+    assert!(!is_ncm(&ncm[..13]));
+}
+
+#[test]
+fn chunk_size_does_not_change_the_audio() {
+    let audio = mp3(200);
+    let ncm = encode_ncm(&audio, Some(&metadata("mp3", json!({}))), None).unwrap();
+    let dump = |chunk_size| {
+        let mut options = DumpOptions::new();
+        options.set_audio_chunk_size(chunk_size);
+        decode_ncm_with_options(&ncm, &options).unwrap().audio
+    };
+    let single_bytes = dump(1);
+    assert_eq!(single_bytes, dump(0x8000));
+    assert!(single_bytes.ends_with(&audio));
+}
+
+#[test]
+fn empty_audio_is_no_audio_data() {
+    let ncm = encode_ncm(&[], Some(&metadata("mp3", json!({}))), Some(b"\x89PNG\r\n\x1a\n")).unwrap();
+    assert!(matches!(decode_ncm_with_options(&ncm, &DumpOptions::new()), Err(DumpError::NoAudioData)));
+}
+
+#[test]
+fn hashes_only_when_asked() {
+    use sha2::{Digest, Sha256};
+    let hex = |data: &[u8]| Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+    let audio = mp3(4);
+    let ncm = encode_ncm(&audio, Some(&metadata("mp3", json!({}))), None).unwrap();
+    let decoded = decode_ncm_with_options(&ncm, &DumpOptions::new()).unwrap();
+    assert!(decoded.audio_hash.is_empty() && decoded.source_hash.is_empty());
+
+    let mut options = DumpOptions::new();
+    options.set_compute_hashes(true);
+    let decoded = decode_ncm_with_options(&ncm, &options).unwrap();
+    assert_eq!(decoded.audio_hash, hex(&audio));
+    assert_eq!(decoded.source_hash, hex(&ncm));
+}
+
+#[test]
+fn unknown_version_bytes_only_fail_in_strict_mode() {
+    let mut ncm = encode_ncm(&mp3(4), Some(&metadata("mp3", json!({}))), None).unwrap();
+    ncm[8..10].copy_from_slice(&[0x02, 0x71]);
+
+    let decoded = decode_ncm_with_options(&ncm, &DumpOptions::new()).unwrap();
+    assert_eq!(decoded.format_version, 0x7102);
+    assert!(decoded.warnings.iter().any(|warning| warning.contains("02 71 instead of 01 70")));
+
+    let mut strict = DumpOptions::new();
+    strict.set_strict(true);
+    assert!(matches!(decode_ncm_with_options(&ncm, &strict), Err(DumpError::UnsupportedVersion(0x7102))));
+    ncm[8..10].copy_from_slice(&[0, 0]);
+    assert!(decode_ncm_with_options(&ncm, &strict).is_ok());
+}
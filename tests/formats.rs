@@ -0,0 +1,67 @@
+mod common;
+
+use common::metadata;
+use ncmdump_wasm::{decode_ncm, encode_ncm, OutputFormat};
+use serde_json::json;
+
+#[test]
+fn passes_m4a_through() {
+    let mut audio = b"\x00\x00\x00\x18ftypM4A \x00\x00\x02\x00isomM4A ".to_vec();
+    audio.extend_from_slice(b"\x00\x00\x00\x08free");
+    let ncm = encode_ncm(&audio, Some(&metadata("m4a", json!({}))), None).unwrap();
+    let decoded = decode_ncm(&ncm).unwrap();
+    assert_eq!(decoded.extension, "m4a");
+    assert_eq!(decoded.format, OutputFormat::M4a);
+    assert_eq!(decoded.audio, audio);
+    assert!(!decoded.tagged);
+}
+
+#[test]
+fn passes_wav_through() {
+    let mut audio = b"RIFF\x2c\x00\x00\x00WAVEfmt \x10\x00\x00\x00\x01\x00\x01\x00\x44\xac\x00\x00\x88\x58\x01\x00\x02\x00\x10\x00".to_vec();
+    audio.extend_from_slice(b"data\x08\x00\x00\x00\x01\x00\x02\x00\x03\x00\x04\x00");
+    let ncm = encode_ncm(&audio, Some(&metadata("wav", json!({}))), None).unwrap();
+    let decoded = decode_ncm(&ncm).unwrap();
+    assert_eq!(decoded.extension, "wav");
+    assert_eq!(decoded.format, OutputFormat::Wav);
+    assert_eq!(decoded.audio, audio);
+    assert!(!decoded.tagged);
+}
+
+#[test]
+fn passes_unknown_audio_through_as_bin() {
+    let audio = b"\x12\x34 not an audio header \x00\x00\x00\x00".to_vec();
+    let ncm = encode_ncm(&audio, Some(&metadata("mp3", json!({}))), None).unwrap();
+    let decoded = decode_ncm(&ncm).unwrap();
+    assert_eq!(decoded.extension, "bin");
+    assert_eq!(decoded.format, OutputFormat::Unknown);
+    assert_eq!(decoded.source_format, "unknown");
+    assert_eq!(decoded.mime_type, "application/octet-stream");
+    assert_eq!(decoded.audio, audio);
+    assert!(!decoded.tagged);
+    if cfg!(feature = "tagging") {
+        assert!(decoded.warnings.iter().any(|warning| warning.contains("Unknown audio format")));
+    }
+}
+
+#[test]
+fn webp_riff_is_not_wav() {
+    let audio = b"RIFF\x10\x00\x00\x00WEBPVP8 \x04\x00\x00\x00".to_vec();
+    let decoded = decode_ncm(&encode_ncm(&audio, None, None).unwrap()).unwrap();
+    assert_ne!(decoded.format, OutputFormat::Wav);
+}
+
+#[test]
+fn finds_flac_behind_an_id3_tag() {
+    let audio = common::id3_flac();
+    let ncm = encode_ncm(&audio, Some(&metadata("flac", json!({}))), None).unwrap();
+    let decoded = decode_ncm(&ncm).unwrap();
+    assert_eq!(decoded.format, OutputFormat::Flac);
+    assert_eq!(decoded.extension, "flac");
+    if cfg!(feature = "tagging") {
+        assert!(decoded.audio.starts_with(b"fLaC"));
+        assert!(decoded.warnings.iter().any(|warning| warning.contains("Stripping 30 bytes")));
+    } else {
+        assert_eq!(decoded.audio, audio);
+    }
+}
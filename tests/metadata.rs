@@ -0,0 +1,82 @@
+mod common;
+
+use common::{metadata_block, metadata_json, mp3, replace_metadata};
+use ncmdump_wasm::{decode_ncm, encode_ncm, DumpError};
+use serde_json::json;
+
+const PREFIX: &str = "163 key(Don't modify):";
+
+/// an mp3 ncm whose metadata block holds `plaintext` behind `prefix`
+fn ncm_with_metadata(prefix: &str, plaintext: &[u8]) -> Vec<u8> {
+    let ncm = encode_ncm(&mp3(2), None, None).unwrap();
+    replace_metadata(&ncm, &metadata_block(prefix, plaintext))
+}
+
+#[test]
+fn ignores_a_trailing_music_ex_section() {
+    let json = metadata_json("mp3", json!({})).to_string();
+    let plaintext = format!("music:{}, \"musicEx\":{{\"alias\":[]}}", json);
+    let decoded = decode_ncm(&ncm_with_metadata(PREFIX, plaintext.as_bytes())).unwrap();
+    assert_eq!(decoded.metadata.unwrap().music_name(), "Title");
+    assert!(decoded.warnings.iter().any(|warning| warning.contains("after the metadata JSON")));
+}
+
+#[test]
+fn tiny_metadata_blocks_are_too_short() {
+    let ncm = encode_ncm(&mp3(2), None, None).unwrap();
+    let cut_prefix: Vec<u8> = PREFIX.bytes().take(10).map(|b| b ^ 0x63).collect();
+    let bare_prefix: Vec<u8> = PREFIX.bytes().map(|b| b ^ 0x63).collect();
+    for block in [cut_prefix, bare_prefix, metadata_block(PREFIX, b"mus")] {
+        let result = decode_ncm(&replace_metadata(&ncm, &block));
+        assert!(matches!(result, Err(DumpError::MetadataTooShort)), "{:?}", result.err());
+    }
+}
+
+#[test]
+fn finds_the_payload_behind_prefixes_of_any_length() {
+    let plaintext = format!("music:{}", metadata_json("mp3", json!({})));
+    for prefix in [PREFIX, "163 key:", "163 key(Don't modify): \n", "163 key(Don't modify, v2):"] {
+        let decoded = decode_ncm(&ncm_with_metadata(prefix, plaintext.as_bytes())).unwrap();
+        assert_eq!(decoded.metadata.unwrap().music_name(), "Title", "prefix {:?}", prefix);
+    }
+}
+
+#[test]
+fn inflates_compressed_metadata() {
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    let json = metadata_json("mp3", json!({})).to_string();
+
+    // gzip around the whole block, `music:` included
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+    gzip.write_all(format!("music:{}", json).as_bytes()).unwrap();
+    let gzipped = gzip.finish().unwrap();
+
+    // zlib around just the json
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+    zlib.write_all(json.as_bytes()).unwrap();
+    let zlibbed = [b"music:".to_vec(), zlib.finish().unwrap()].concat();
+
+    for plaintext in [gzipped, zlibbed] {
+        let decoded = decode_ncm(&ncm_with_metadata(PREFIX, &plaintext)).unwrap();
+        assert_eq!(decoded.metadata.unwrap().music_name(), "Title");
+    }
+}
+
+#[test]
+fn reads_every_artist_shape() {
+    let shapes = [
+        json!([["Artist", 2], ["Other", 3]]),
+        json!(["Artist", "Other"]),
+        json!([{"name": "Artist", "id": 2}, {"name": "Other"}]),
+        json!([["Artist", 2], "Other"]),
+    ];
+    for artists in shapes {
+        let json = metadata_json("mp3", json!({ "artist": artists.clone() }));
+        let decoded = decode_ncm(&ncm_with_metadata(PREFIX, format!("music:{}", json).as_bytes())).unwrap();
+        let names: Vec<String> = decoded.metadata.unwrap().artists().iter().map(|artist| artist.name()).collect();
+        assert_eq!(names, ["Artist", "Other"], "artists {}", artists);
+    }
+}
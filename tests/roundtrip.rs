@@ -0,0 +1,60 @@
+mod common;
+
+use common::{flac, metadata, mp3};
+use ncmdump_wasm::{decode_ncm, decode_ncm_with_options, dump_slice, encode_ncm, DumpOptions};
+use serde_json::json;
+
+/// options that leave the decrypted audio untouched
+fn untagged() -> DumpOptions {
+    let mut options = DumpOptions::new();
+    options.set_embed_metadata(false);
+    options.set_embed_cover(false);
+    options
+}
+
+#[test]
+fn round_trips_bare_audio() {
+    for audio in [mp3(8), flac()] {
+        let decoded = decode_ncm(&encode_ncm(&audio, None, None).unwrap()).unwrap();
+        assert_eq!(decoded.audio, audio);
+        assert!(decoded.metadata.is_none());
+        assert!(decoded.image.is_none());
+        assert!(!decoded.tagged);
+    }
+}
+
+#[test]
+fn dump_slice_matches_decode_ncm() {
+    let ncm = encode_ncm(&mp3(8), Some(&metadata("mp3", json!({}))), None).unwrap();
+    let mut output = dump_slice(&ncm, None);
+    assert_eq!(output.result(), "ok");
+    assert_eq!(output.extension(), "mp3");
+    assert_eq!(output.take_data(), decode_ncm(&ncm).unwrap().audio);
+}
+
+#[test]
+fn round_trips_metadata_and_cover() {
+    let audio = mp3(8);
+    let cover = b"\xFF\xD8\xFF\xE0 not really a jpeg".to_vec();
+    let ncm = encode_ncm(&audio, Some(&metadata("mp3", json!({}))), Some(&cover)).unwrap();
+
+    let decoded = decode_ncm_with_options(&ncm, &untagged()).unwrap();
+    assert_eq!(decoded.audio, audio);
+    assert_eq!(decoded.audio_size, audio.len() as u64);
+    let metadata = decoded.metadata.unwrap();
+    assert_eq!(metadata.music_name(), "Title");
+    assert_eq!(metadata.album(), "Album");
+    let image = decoded.image.unwrap();
+    assert_eq!(image.mime(), "image/jpeg");
+    assert_eq!(image.bytes(), cover);
+}
+
+#[test]
+fn round_trips_metadata_without_cover() {
+    let audio = flac();
+    let ncm = encode_ncm(&audio, Some(&metadata("flac", json!({}))), None).unwrap();
+    let decoded = decode_ncm_with_options(&ncm, &untagged()).unwrap();
+    assert_eq!(decoded.audio, audio);
+    assert_eq!(decoded.metadata.unwrap().music_id(), 1);
+    assert!(decoded.image.is_none());
+}
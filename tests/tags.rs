@@ -0,0 +1,222 @@
+//! tags read back from dumped audio
+#![cfg(feature = "tagging")]
+
+mod common;
+
+use std::io::Cursor;
+
+use common::{flac, metadata, mp3};
+use ncmdump_wasm::{decode_ncm, decode_ncm_with_options, encode_ncm, CoverFormat, CoverPolicy, DumpOptions};
+use serde_json::{json, Value};
+
+/// `mp3(4)` behind the ID3 tag `tag`
+fn tagged_mp3(tag: &id3::Tag, version: id3::Version) -> Vec<u8> {
+    let mut audio = vec![];
+    tag.write_to(&mut audio, version).unwrap();
+    audio.extend_from_slice(&mp3(4));
+    audio
+}
+
+fn dump_mp3(audio: &[u8], extra: Value) -> id3::Tag {
+    let ncm = encode_ncm(audio, Some(&metadata("mp3", extra)), None).unwrap();
+    id3::Tag::read_from(Cursor::new(decode_ncm(&ncm).unwrap().audio)).unwrap()
+}
+
+fn dump_flac(extra: Value) -> metaflac::Tag {
+    let ncm = encode_ncm(&flac(), Some(&metadata("flac", extra)), None).unwrap();
+    metaflac::Tag::read_from(&mut Cursor::new(decode_ncm(&ncm).unwrap().audio)).unwrap()
+}
+
+fn translated_name(tag: &id3::Tag) -> Option<&str> {
+    tag.extended_texts().find(|text| text.description == "TRANSLATED_NAME").map(|text| text.value.as_str())
+}
+
+#[test]
+fn keeps_existing_id3_frames() {
+    let mut existing = id3::Tag::new();
+    existing.set_text("TENC", "LAME 3.100");
+    existing.add_extended_text("REPLAYGAIN_TRACK_GAIN", "-6.5 dB");
+    existing.set_title("Old title");
+
+    let tag = dump_mp3(&tagged_mp3(&existing, id3::Version::Id3v24), json!({}));
+    assert_eq!(tag.get("TENC").and_then(|frame| frame.content().text()), Some("LAME 3.100"));
+    assert!(tag.extended_texts().any(|text| text.description == "REPLAYGAIN_TRACK_GAIN" && text.value == "-6.5 dB"));
+    assert_eq!(tag.title(), Some("Title"));
+}
+
+#[test]
+fn writes_every_artist_into_tpe1() {
+    let artists = json!({ "artist": [["Artist", 2], ["Other", 3]] });
+
+    let tag = dump_mp3(&mp3(4), artists.clone());
+    assert_eq!(tag.version(), id3::Version::Id3v24);
+    let values: Vec<&str> = tag.artist().unwrap().split('\0').collect();
+    assert_eq!(values, ["Artist", "Other"]);
+
+    // v2.3 has no multi-value frames, players there expect `/`
+    let tag = dump_mp3(&tagged_mp3(&id3::Tag::new(), id3::Version::Id3v23), artists);
+    assert_eq!(tag.artist(), Some("Artist/Other"));
+}
+
+#[test]
+fn writes_the_album_artist() {
+    let album_artist = json!({ "albumArtist": "Various Artists" });
+    assert_eq!(dump_mp3(&mp3(4), album_artist.clone()).album_artist(), Some("Various Artists"));
+    assert_eq!(dump_flac(album_artist).get_vorbis("ALBUMARTIST").unwrap().collect::<Vec<_>>(), ["Various Artists"]);
+
+    // without one the first track artist stands in
+    assert_eq!(dump_mp3(&mp3(4), json!({})).album_artist(), Some("Artist"));
+    assert_eq!(dump_flac(json!({})).get_vorbis("ALBUMARTIST").unwrap().collect::<Vec<_>>(), ["Artist"]);
+}
+
+#[test]
+fn writes_translated_names_only_when_there_are_some() {
+    let names = json!({ "transNames": ["Translated"] });
+    assert_eq!(translated_name(&dump_mp3(&mp3(4), names.clone())), Some("Translated"));
+    assert_eq!(translated_name(&dump_mp3(&mp3(4), json!({ "transNames": [] }))), None);
+
+    let tag = dump_flac(names);
+    assert_eq!(tag.get_vorbis("TRANSLATEDTITLE").unwrap().collect::<Vec<_>>(), ["Translated"]);
+    assert!(dump_flac(json!({ "transNames": [] })).get_vorbis("TRANSLATEDTITLE").is_none());
+}
+
+#[test]
+fn if_missing_keeps_a_pre_embedded_cover() {
+    let mut existing = id3::Tag::new();
+    existing.add_picture(id3::frame::Picture {
+        mime_type: "image/jpeg".to_string(),
+        picture_type: id3::frame::PictureType::CoverFront,
+        description: String::new(),
+        data: b"\xFF\xD8\xFF\xE0 original".to_vec(),
+    });
+    let audio = tagged_mp3(&existing, id3::Version::Id3v24);
+    let ours = b"\xFF\xD8\xFF\xE0 ours".to_vec();
+    let ncm = encode_ncm(&audio, Some(&metadata("mp3", json!({}))), Some(&ours)).unwrap();
+
+    let dump = |policy| {
+        let mut options = DumpOptions::new();
+        options.set_cover_policy(policy);
+        let decoded = decode_ncm_with_options(&ncm, &options).unwrap();
+        let tag = id3::Tag::read_from(Cursor::new(&decoded.audio)).unwrap();
+        let pictures: Vec<Vec<u8>> = tag.pictures().map(|picture| picture.data.clone()).collect();
+        (decoded.has_cover, pictures)
+    };
+    assert_eq!(dump(CoverPolicy::IfMissing), (false, vec![b"\xFF\xD8\xFF\xE0 original".to_vec()]));
+    assert_eq!(dump(CoverPolicy::Always), (true, vec![ours.clone()]));
+}
+
+#[test]
+fn writes_the_comment_only_when_there_is_one() {
+    let comment = json!({ "comment": "Live at the venue" });
+    let tag = dump_mp3(&mp3(4), comment.clone());
+    assert_eq!(tag.comments().map(|comment| comment.text.as_str()).collect::<Vec<_>>(), ["Live at the venue"]);
+    assert_eq!(dump_mp3(&mp3(4), json!({ "comment": "" })).comments().count(), 0);
+    assert_eq!(dump_mp3(&mp3(4), json!({})).comments().count(), 0);
+
+    let tag = dump_flac(comment);
+    assert_eq!(tag.get_vorbis("DESCRIPTION").unwrap().collect::<Vec<_>>(), ["Live at the venue"]);
+    assert!(dump_flac(json!({ "description": "" })).get_vorbis("DESCRIPTION").is_none());
+}
+
+#[test]
+fn cover_only_output_has_no_text_tags() {
+    let cover = b"\xFF\xD8\xFF\xE0 cover".to_vec();
+    let mut options = DumpOptions::new();
+    options.set_embed_metadata(false);
+
+    let ncm = encode_ncm(&mp3(4), Some(&metadata("mp3", json!({}))), Some(&cover)).unwrap();
+    let decoded = decode_ncm_with_options(&ncm, &options).unwrap();
+    assert!(decoded.has_cover && !decoded.has_metadata);
+    let tag = id3::Tag::read_from(Cursor::new(&decoded.audio)).unwrap();
+    assert_eq!(tag.pictures().count(), 1);
+    assert!(tag.title().is_none() && tag.artist().is_none() && tag.album().is_none());
+
+    let ncm = encode_ncm(&flac(), Some(&metadata("flac", json!({}))), Some(&cover)).unwrap();
+    let decoded = decode_ncm_with_options(&ncm, &options).unwrap();
+    let tag = metaflac::Tag::read_from(&mut Cursor::new(&decoded.audio)).unwrap();
+    assert_eq!(tag.pictures().count(), 1);
+    assert!(tag.get_vorbis("TITLE").is_none() && tag.get_vorbis("ARTIST").is_none());
+}
+
+/// MIME type of the cover as reported by the dump, then as embedded into mp3 and into flac
+fn cover_mime_types(cover: &[u8]) -> (String, String, String) {
+    let ncm = encode_ncm(&mp3(4), Some(&metadata("mp3", json!({}))), Some(cover)).unwrap();
+    let decoded = decode_ncm(&ncm).unwrap();
+    let tag = id3::Tag::read_from(Cursor::new(&decoded.audio)).unwrap();
+    let mp3_mime = tag.pictures().next().unwrap().mime_type.clone();
+
+    let ncm = encode_ncm(&flac(), Some(&metadata("flac", json!({}))), Some(cover)).unwrap();
+    let tag = metaflac::Tag::read_from(&mut Cursor::new(decode_ncm(&ncm).unwrap().audio)).unwrap();
+    let flac_mime = tag.pictures().next().unwrap().mime_type.clone();
+    (decoded.image.unwrap().mime(), mp3_mime, flac_mime)
+}
+
+#[test]
+fn embeds_webp_covers_as_webp() {
+    let webp = b"RIFF\x1a\x00\x00\x00WEBPVP8L\x0d\x00\x00\x00\x2f\x00\x00\x00\x10\x07\x10\x11\x11\x88\x88\xfe\x07\x00";
+    let mime = "image/webp".to_string();
+    assert_eq!(cover_mime_types(webp), (mime.clone(), mime.clone(), mime));
+}
+
+#[test]
+fn embeds_exif_jpeg_covers_as_jpeg() {
+    // APP1 Exif segment where JFIF files have APP0, then a quantization table
+    let mut exif = b"\xFF\xD8\xFF\xE1\x00\x10Exif\x00\x00MM\x00\x2A\x00\x00\x00\x08".to_vec();
+    exif.extend_from_slice(b"\xFF\xDB\x00\x43\x00");
+    exif.extend_from_slice(&[1; 64]);
+    exif.extend_from_slice(b"\xFF\xD9");
+    let mime = "image/jpeg".to_string();
+    assert_eq!(cover_mime_types(&exif), (mime.clone(), mime.clone(), mime));
+}
+
+#[test]
+fn embeds_png_covers_as_jpeg_when_asked() {
+    let ncm = encode_ncm(&mp3(4), Some(&metadata("mp3", json!({}))), Some(&common::png(20, 12))).unwrap();
+    let mut options = DumpOptions::new();
+    options.set_cover_format(CoverFormat::Jpeg);
+    let decoded = decode_ncm_with_options(&ncm, &options).unwrap();
+    let tag = id3::Tag::read_from(Cursor::new(&decoded.audio)).unwrap();
+    let picture = tag.pictures().next().unwrap();
+    assert_eq!(picture.mime_type, "image/jpeg");
+    assert!(picture.data.starts_with(&[0xFF, 0xD8]) && picture.data.ends_with(&[0xFF, 0xD9]));
+}
+
+#[test]
+fn truncated_flac_metadata_fails_without_panicking() {
+    // a FLAC stream with STREAMINFO, VORBIS_COMMENT and PICTURE blocks, as written by a dump
+    let ncm = encode_ncm(&flac(), Some(&metadata("flac", json!({}))), Some(b"\xFF\xD8\xFF\xE0 cover")).unwrap();
+    let tagged = decode_ncm(&ncm).unwrap().audio;
+
+    for len in 1..tagged.len() {
+        let ncm = encode_ncm(&tagged[..len], Some(&metadata("flac", json!({}))), None).unwrap();
+        let _ = decode_ncm(&ncm);
+    }
+}
+
+#[test]
+fn malformed_flac_comments_are_a_tag_error() {
+    // a comment without `=`, metaflac would panic splitting it
+    let mut audio = flac();
+    audio[4] = 0x00;
+    let comment = [&3u32.to_le_bytes()[..], b"abc", &1u32.to_le_bytes(), &5u32.to_le_bytes(), b"TITLE"].concat();
+    let mut block = vec![0x84, 0, 0, comment.len() as u8];
+    block.extend_from_slice(&comment);
+    audio.splice(42..42, block);
+
+    let ncm = encode_ncm(&audio, Some(&metadata("flac", json!({}))), None).unwrap();
+    let decoded = decode_ncm(&ncm).unwrap();
+    assert!(decoded.tag_error.is_some());
+    assert_eq!(decoded.audio, audio);
+}
+
+#[test]
+fn writes_the_year_into_the_frame_of_the_tag_version() {
+    let year = json!({ "year": 2014 });
+    let tag = dump_mp3(&mp3(4), year.clone());
+    assert_eq!(tag.date_recorded().map(|date| date.year), Some(2014));
+    assert!(tag.get("TYER").is_none());
+
+    let tag = dump_mp3(&tagged_mp3(&id3::Tag::new(), id3::Version::Id3v23), year);
+    assert_eq!(tag.year(), Some(2014));
+    assert!(tag.get("TDRC").is_none());
+}